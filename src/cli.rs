@@ -0,0 +1,195 @@
+//! Command line arguments. There aren't many, so they're parsed by hand.
+
+use std::path::PathBuf;
+
+pub const USAGE: &str = "\
+usage: nvim-matrix-bot [--config <path>] [<command>]
+
+commands:
+  run             Run the bot, using the session saved by `login` if there is one (the default)
+  login           Log in and save the session for `run` to use
+  help <query>    Print the docs `!help <query>` would link to, without a homeserver
+  gif <query>     Search for gifs like `!gif <query>` would, without sending any
+  check-config    Make sure the config is valid
+  repl [--online] [room]
+                  Run the commands typed in (as if they were in `room`, a room ID) and print the
+                  replies, without the network. Gif searches find a made-up gif (or ones from
+                  the local library) unless --online is given
+
+options:
+  -c, --config <path>    The config file, instead of ./config.toml or ./config.json
+  -h, --help             Print this";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Subcommand {
+    Run,
+    Login,
+    Help(String),
+    Gif(String),
+    CheckConfig,
+    /// Commands from stdin, optionally in a room from the config
+    Repl {
+        room_id: Option<String>,
+        /// Whether to really search for (and download) gifs
+        online: bool,
+    },
+    /// `--help`, as opposed to `help <query>`
+    Usage,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Cli {
+    pub config: Option<PathBuf>,
+    pub subcommand: Subcommand,
+}
+
+/// Parses the arguments (without the program name first), with an error message saying what's
+/// wrong if they don't make sense.
+pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Cli, String> {
+    let mut config = None;
+    let mut words = vec![];
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        // Everything after these is the query, even things like `-h` (which is a help tag)
+        if matches!(words.first().map(String::as_str), Some("help" | "gif")) {
+            words.push(arg);
+            continue;
+        }
+        match arg.as_str() {
+            "-c" | "--config" => match args.next() {
+                Some(path) => config = Some(PathBuf::from(path)),
+                None => return Err(format!("{} needs a path", arg)),
+            },
+            "-h" | "--help" => {
+                return Ok(Cli {
+                    config,
+                    subcommand: Subcommand::Usage,
+                })
+            }
+            // From before there were subcommands
+            "--check-config" => words.push("check-config".to_string()),
+            _ if arg.starts_with('-') && words.is_empty() => {
+                return Err(format!("unknown option {}", arg))
+            }
+            _ => words.push(arg),
+        }
+    }
+
+    let query = |words: &[String], command: &str| {
+        if words.is_empty() {
+            Err(format!("{} needs a query", command))
+        } else {
+            Ok(words.join(" "))
+        }
+    };
+    let no_more = |words: &[String], subcommand: Subcommand| match words.first() {
+        Some(word) => Err(format!("unexpected argument {}", word)),
+        None => Ok(subcommand),
+    };
+
+    let subcommand = match words.split_first() {
+        None => Subcommand::Run,
+        Some((command, rest)) => match command.as_str() {
+            "run" => no_more(rest, Subcommand::Run)?,
+            "login" => no_more(rest, Subcommand::Login)?,
+            "check-config" => no_more(rest, Subcommand::CheckConfig)?,
+            "help" => Subcommand::Help(query(rest, command)?),
+            "gif" => Subcommand::Gif(query(rest, command)?),
+            "repl" => {
+                let online = rest.iter().any(|word| word == "--online");
+                let rest: Vec<&String> = rest.iter().filter(|word| *word != "--online").collect();
+                let room_id = match rest[..] {
+                    [] => None,
+                    // Resolving an alias needs the homeserver
+                    [room_id] if room_id.starts_with('#') => {
+                        return Err(format!(
+                            "repl needs a room ID, not an alias like {}",
+                            room_id
+                        ))
+                    }
+                    [room_id] => Some(room_id.clone()),
+                    [_, extra, ..] => return Err(format!("unexpected argument {}", extra)),
+                };
+                Subcommand::Repl { room_id, online }
+            }
+            _ => return Err(format!("unknown command {}", command)),
+        },
+    };
+
+    Ok(Cli { config, subcommand })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse_str(args: &str) -> Result<Cli, String> {
+        parse(args.split_whitespace().map(String::from))
+    }
+
+    #[test]
+    fn cli_works() {
+        let cli = |config: Option<&str>, subcommand| {
+            Ok(Cli {
+                config: config.map(PathBuf::from),
+                subcommand,
+            })
+        };
+
+        assert_eq!(parse_str(""), cli(None, Subcommand::Run));
+        assert_eq!(parse_str("run"), cli(None, Subcommand::Run));
+        assert_eq!(
+            parse_str("-c bot.toml login"),
+            cli(Some("bot.toml"), Subcommand::Login)
+        );
+        assert_eq!(
+            parse_str("check-config --config bot.toml"),
+            cli(Some("bot.toml"), Subcommand::CheckConfig)
+        );
+        assert_eq!(
+            parse_str("--check-config"),
+            cli(None, Subcommand::CheckConfig)
+        );
+        assert_eq!(
+            parse_str("help -- :h CTRL-W"),
+            cli(None, Subcommand::Help("-- :h CTRL-W".to_string()))
+        );
+        assert_eq!(
+            parse_str("gif happy cat"),
+            cli(None, Subcommand::Gif("happy cat".to_string()))
+        );
+        for tag in ["-h", "--help", "-c"] {
+            assert_eq!(
+                parse_str(&format!("help {}", tag)),
+                cli(None, Subcommand::Help(tag.to_string()))
+            );
+        }
+        assert_eq!(
+            parse_str("-c bot.toml gif -c --config"),
+            cli(Some("bot.toml"), Subcommand::Gif("-c --config".to_string()))
+        );
+        let repl = |room_id: Option<&str>, online| Subcommand::Repl {
+            room_id: room_id.map(String::from),
+            online,
+        };
+        assert_eq!(parse_str("repl"), cli(None, repl(None, false)));
+        assert_eq!(
+            parse_str("repl !a:matrix.org"),
+            cli(None, repl(Some("!a:matrix.org"), false))
+        );
+        assert_eq!(
+            parse_str("repl --online !a:matrix.org"),
+            cli(None, repl(Some("!a:matrix.org"), true))
+        );
+        assert_eq!(parse_str("repl --online"), cli(None, repl(None, true)));
+        assert_eq!(parse_str("--help gif"), cli(None, Subcommand::Usage));
+
+        assert!(parse_str("help").is_err());
+        assert!(parse_str("run now").is_err());
+        assert!(parse_str("sandwich").is_err());
+        assert!(parse_str("--verbose").is_err());
+        assert!(parse_str("--config").is_err());
+        assert!(parse_str("repl #a:matrix.org").is_err());
+        assert!(parse_str("repl !a:matrix.org !b:matrix.org").is_err());
+    }
+}
@@ -1,18 +1,58 @@
 use fancy_regex::Regex;
+use std::collections::HashSet;
+
+use crate::scan;
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum Command<'a> {
-    Help { docs: Vec<&'a str> },
-    Sandwich { to: &'a str },
-    Url { url: &'a str },
-    Gif { search: String, giphy: bool },
+    Help {
+        docs: Vec<String>,
+    },
+    Sandwich {
+        to: &'a str,
+    },
+    Url {
+        url: &'a str,
+    },
+    /// `provider` is set when a specific one was asked for, like with `!giphy`
+    Gif {
+        search: String,
+        provider: Option<&'a str>,
+    },
+    /// `!config [setting [value]]`, for changing room settings
+    Config {
+        setting: Option<&'a str>,
+        value: Option<&'a str>,
+    },
 }
 
+impl Command<'_> {
+    /// What the command is called, for per-command settings like cooldowns. Every URL command
+    /// counts as `url`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Help { .. } => "help",
+            Command::Sandwich { .. } => "sandwich",
+            Command::Url { .. } => "url",
+            Command::Gif { .. } => "gif",
+            Command::Config { .. } => "config",
+        }
+    }
+
+    /// Identifies the command along with its arguments, so an edited message can tell which of
+    /// its commands changed.
+    pub fn key(&self) -> String {
+        format!("{:?}", self)
+    }
+}
+
+/// Most commands handled for a single message, so one message can't make the bot spam a room.
+const MAX_COMMANDS: usize = 5;
+/// Most help docs looked up for a single message.
+const MAX_HELP_DOCS: usize = 10;
+
 pub struct CommandParser {
     command_regex: Regex,
-    backticked_help_regex: Regex,
-    codeblock_help_regex: Regex, // For when we're parsing a formatted_body, and instead of
-    // backticks we get something like <code>:help blah</code>
     url_commands_json: serde_json::Value,
 }
 
@@ -23,96 +63,163 @@ impl CommandParser {
 
         Self {
             url_commands_json: json,
-            command_regex: Regex::new(r"^!(\w+)( *)(.*)").unwrap(),
-            backticked_help_regex: Regex::new(r"(?:`:(?:help|h|he|hel) (((?!`).)*)`)").unwrap(),
-            codeblock_help_regex: Regex::new(
-                r"(?:<code>:(?:help|h|he|hel) (((?!(<\/code>)).)*)<\/code>)",
-            )
-            .unwrap(),
+            command_regex: Regex::new(r"^(\w+)( *)(.*)").unwrap(),
         }
     }
 
-    pub fn parse<'a>(&'a self, string: &'a str) -> Option<Command<'a>> {
-        if self.command_regex.is_match(string).unwrap() {
-            let mut iter = self.command_regex.captures_iter(string);
-            let caps = iter.next()?.ok()?;
-            let command = caps.get(1)?.as_str();
-            let args: Option<Vec<&str>> = caps.get(3).map(|args| {
-                args.as_str()
-                    .split(' ')
-                    .filter(|str| !str.is_empty())
-                    .collect()
-            });
-
-            use Command::*;
-            match command {
-                "help" | "h" | "he" | "hel" => {
-                    let mut docs = args?;
-
-                    // Get rid of duplicates (see https://stackoverflow.com/a/47636725)
-                    docs.sort_unstable();
-                    docs.dedup();
-
-                    if !docs.is_empty() {
-                        Some(Help { docs })
-                    } else {
-                        None
-                    }
-                }
-                "sandwich" => {
-                    let args = args?;
-                    Some(Sandwich { to: args[0] })
-                }
-                "gif" => {
-                    let args = args?;
-                    Some(Gif {
-                        search: args.join(" "),
-                        giphy: false,
-                    })
+    /// Parses a single `name args...` command (without the prefix).
+    fn parse_command<'a>(&'a self, line: &'a str) -> Option<Command<'a>> {
+        let caps = self.command_regex.captures(line).ok()??;
+        let command = caps.get(1)?.as_str();
+        let args: Vec<&str> = caps
+            .get(3)?
+            .as_str()
+            .split(' ')
+            .filter(|str| !str.is_empty())
+            .collect();
+
+        use Command::*;
+        match command {
+            "help" | "h" | "he" | "hel" => {
+                let docs: Vec<String> = args.into_iter().map(String::from).collect();
+
+                if !docs.is_empty() {
+                    Some(Help { docs })
+                } else {
+                    None
                 }
-                "giphy" => {
-                    let args = args?;
-                    Some(Gif {
-                        search: args.join(" "),
-                        giphy: true,
-                    })
+            }
+            "sandwich" => Some(Sandwich { to: args.first()? }),
+            "gif" | "giphy" if !args.is_empty() => Some(Gif {
+                search: args.join(" "),
+                provider: (command != "gif").then_some(command),
+            }),
+            "config" => Some(Config {
+                setting: args.first().copied(),
+                value: args.get(1).copied(),
+            }),
+            x => self.url_commands_json.get(x).map(|url| Url {
+                url: url.as_str().unwrap(),
+            }),
+        }
+    }
+
+    /// Parses the text following a mention of the bot, like the `help foo` in
+    /// `@nvim-bot: help foo`. Only the first line is used, and the prefix is optional.
+    pub fn parse_mention<'a>(&'a self, string: &'a str, prefix: &str) -> Option<Command<'a>> {
+        let line = string.lines().next()?.trim();
+        self.parse_command(line.strip_prefix(prefix).unwrap_or(line))
+    }
+
+    /// Parses a message body, `html` being whether it's a `formatted_body` or a plain one.
+    ///
+    /// Returns every command in the message (`prefix`ed commands at the start of a line, in
+    /// order, followed by any inline `:h` references), with all the help lookups merged into a
+    /// single [`Command::Help`] and duplicates removed.
+    pub fn parse<'a>(&'a self, string: &'a str, html: bool, prefix: &str) -> Vec<Command<'a>> {
+        let mut commands: Vec<Command<'a>> = vec![];
+        let mut docs: Vec<String> = vec![];
+
+        // An empty prefix would make every line a command, so that just disables them instead
+        let lines = string
+            .lines()
+            .filter_map(|line| line.strip_prefix(prefix).filter(|_| !prefix.is_empty()));
+        for line in lines {
+            match self.parse_command(line) {
+                Some(Command::Help { docs: help_docs }) => {
+                    if docs.is_empty() {
+                        // Placeholder so the help reply keeps its place in the order
+                        commands.push(Command::Help { docs: vec![] });
+                    }
+                    docs.extend(help_docs);
                 }
-                x => self.url_commands_json.get(x).map(|url| Url {
-                    url: url.as_str().unwrap(),
-                }),
+                Some(cmd) if !commands.contains(&cmd) => commands.push(cmd),
+                _ => {}
             }
-        } else if self.backticked_help_regex.is_match(string).unwrap() {
-            let mut docs = self
-                .backticked_help_regex
-                .captures_iter(string)
-                .map(|caps| {
-                    let caps = caps.as_ref().unwrap();
-                    caps.get(1).unwrap().as_str()
-                })
-                .collect::<Vec<&str>>();
-
-            // Get rid of duplicates (see https://stackoverflow.com/a/47636725)
-            docs.sort_unstable();
-            docs.dedup();
-
-            Some(Command::Help { docs })
-        } else if self.codeblock_help_regex.is_match(string).unwrap() {
-            let mut docs = self
-                .codeblock_help_regex
-                .captures_iter(string)
-                .map(|caps| {
-                    let caps = caps.as_ref().unwrap();
-                    caps.get(1).unwrap().as_str()
-                })
-                .collect::<Vec<&str>>();
-
-            // Get rid of duplicates (see https://stackoverflow.com/a/47636725)
-            docs.sort_unstable();
-            docs.dedup();
-
-            Some(Command::Help { docs })
+        }
+
+        let inline_docs = if html {
+            scan::html_help_refs(string)
         } else {
-            None
+            scan::markdown_help_refs(string)
+        };
+        if docs.is_empty() && !inline_docs.is_empty() {
+            commands.push(Command::Help { docs: vec![] });
+        }
+        docs.extend(inline_docs);
+
+        let mut seen = HashSet::new();
+        docs.retain(|doc| seen.insert(doc.clone()));
+        docs.truncate(MAX_HELP_DOCS);
+
+        for cmd in &mut commands {
+            if let Command::Help { docs: help_docs } = cmd {
+                *help_docs = std::mem::take(&mut docs);
+            }
         }
+
+        commands.truncate(MAX_COMMANDS);
+        commands
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_every_command() {
+        let parser = CommandParser::new();
+        assert_eq!(
+            parser.parse(
+                "!gif cat\n!help foo bar\n`:h foo` `:h baz`\n!gif cat",
+                false,
+                "!"
+            ),
+            vec![
+                Command::Gif {
+                    search: "cat".to_string(),
+                    provider: None
+                },
+                Command::Help {
+                    docs: vec!["foo".to_string(), "bar".to_string(), "baz".to_string()]
+                },
+            ]
+        );
+        assert_eq!(
+            parser.parse("`:h foo`\n?sandwich you\n!sandwich me", false, "?"),
+            vec![
+                Command::Sandwich { to: "you" },
+                Command::Help {
+                    docs: vec!["foo".to_string()]
+                },
+            ]
+        );
+        assert!(parser.parse("!sandwich", false, "!").is_empty());
+        assert!(parser.parse("!sandwich you", false, "").is_empty());
+        assert_eq!(parser.parse(&"!guide\n".repeat(10), false, "!").len(), 1);
+        assert_eq!(
+            parser.parse_mention("help foo\nthanks", "!"),
+            Some(Command::Help {
+                docs: vec!["foo".to_string()]
+            })
+        );
+        assert_eq!(
+            parser.parse_mention("!sandwich you", "!"),
+            Some(Command::Sandwich { to: "you" })
+        );
+        assert_eq!(
+            parser.parse("!config prefix ?", false, "!"),
+            vec![Command::Config {
+                setting: Some("prefix"),
+                value: Some("?")
+            }]
+        );
+
+        // A typo fixed elsewhere in the message doesn't change the command
+        let key = |message| parser.parse(message, false, "!")[0].key();
+        assert_eq!(key("!gif cat\nteh"), key("!gif cat\nthe"));
+        assert_ne!(key("!gif cat"), key("!gif cats"));
+        assert_ne!(key("!gif cat"), key("!giphy cat"));
     }
 }
@@ -1,22 +1,874 @@
-use serde::Deserialize;
-use std::collections::HashMap;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value as Json;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::gif::{Rating, PROVIDER_NAMES};
+use crate::media::UploadMode;
 
 pub type RoomId = String;
 
+const DEFAULT_PREFIX: &str = "!";
+
+/// Where the config is looked for when it isn't given, in order
+const DEFAULT_PATHS: &[&str] = &["./config.toml", "./config.json"];
+
+/// Environment variables starting with this override settings, e.g. `NVIM_BOT_STATE_DIR`
+/// overrides `state_dir`
+pub const ENV_PREFIX: &str = "NVIM_BOT_";
+
+/// Environment variables from before there was a config file, and the settings they override
+const LEGACY_ENV_VARS: &[(&str, &str)] = &[
+    ("MATRIX_HOMESERVER", "homeserver"),
+    ("TENOR_API_KEY", "tenor_api_key"),
+    ("GIPHY_API_KEY", "giphy_api_key"),
+];
+
+fn default_homeserver() -> String {
+    "https://matrix.org".to_string()
+}
+
+fn default_prefix() -> String {
+    DEFAULT_PREFIX.to_string()
+}
+
+fn default_delete_reactions() -> Vec<String> {
+    vec!["❌".to_string(), "🗑️".to_string()]
+}
+
+fn default_reroll_reaction() -> String {
+    "🔁".to_string()
+}
+
+fn default_gif_provider() -> String {
+    "tenor".to_string()
+}
+
+fn default_gif_fallback() -> Vec<String> {
+    vec!["tenor".to_string(), "giphy".to_string()]
+}
+
+fn default_state_dir() -> String {
+    "./state".to_string()
+}
+
+fn default_media_cache_ttl() -> u64 {
+    // A week
+    7 * 24 * 60 * 60
+}
+
+fn default_max_gif_size() -> u64 {
+    8 * 1024 * 1024
+}
+
+fn default_cooldowns() -> HashMap<String, u64> {
+    HashMap::from([("gif".to_string(), 10)])
+}
+
+fn default_gif_fetches_per_minute() -> usize {
+    30
+}
+
+fn default_config_power_level() -> i64 {
+    // Moderators
+    50
+}
+
+/// Compares reaction keys, ignoring variation selectors since clients aren't consistent about
+/// sending emoji with or without them.
+fn same_emoji(a: &str, b: &str) -> bool {
+    a.replace('\u{fe0f}', "") == b.replace('\u{fe0f}', "")
+}
+
+/// Room settings that can be changed with `!config`
+pub const ROOM_SETTINGS: &[&str] = &[
+    "gifs",
+    "sandwich",
+    "prefix",
+    "redact_replies",
+    "gif_provider",
+    "gif_rating",
+    "gif_video",
+    "throttle_replies",
+];
+
+/// Turns the `value` of a room setting as it's written in `!config` into JSON, which still
+/// has to be checked with [`RoomConfig::apply`].
+pub fn setting_value(key: &str, value: &str) -> Result<Json, String> {
+    match key {
+        "gifs" | "sandwich" | "redact_replies" | "gif_video" | "throttle_replies" => match value {
+            "on" | "true" | "yes" => Ok(Json::Bool(true)),
+            "off" | "false" | "no" => Ok(Json::Bool(false)),
+            _ => Err(format!("{} has to be on or off", key)),
+        },
+        // These fall back to the global settings when they aren't set
+        "prefix" | "gif_provider" if value == "default" => Ok(Json::Null),
+        "prefix" | "gif_provider" | "gif_rating" => Ok(Json::from(value)),
+        _ => Err(format!("there's no setting called {}", key)),
+    }
+}
+
+/// Something wrong with a setting, along with where it is (like `rooms.!abc:matrix.org.gifs`)
+pub type SettingError = serde_path_to_error::Error<serde_json::Error>;
+
+/// What a config (or credentials) file is written in, which goes by its extension.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Toml,
+}
+
+impl Format {
+    pub fn of(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => Format::Toml,
+            _ => Format::Json,
+        }
+    }
+
+    /// Parses `contents` as JSON, whatever format they're in.
+    fn read(self, contents: &str) -> Result<Json, ConfigError> {
+        let invalid = |message: String| ConfigError::Invalid {
+            key: String::new(),
+            message,
+        };
+        match self {
+            Format::Json => serde_json::from_str(contents).map_err(|err| invalid(err.to_string())),
+            Format::Toml => toml::from_str(contents).map_err(|err| invalid(err.to_string())),
+        }
+    }
+}
+
+/// The config file to use when there isn't one given, if there is one.
+pub fn default_path() -> Option<&'static Path> {
+    DEFAULT_PATHS
+        .iter()
+        .map(Path::new)
+        .find(|path| path.exists())
+}
+
+/// Settings that are strings, which environment variables set as is, even when they look like
+/// JSON (an API key could be all digits)
+const STRING_SETTINGS: &[&str] = &[
+    "homeserver",
+    "credentials_file",
+    "tenor_api_key",
+    "giphy_api_key",
+    "prefix",
+    "reroll_reaction",
+    "state_dir",
+    "local_gifs",
+    "gif_provider",
+];
+
+/// Settings from environment variables, as `(setting, variable, value)`. Values for
+/// [`STRING_SETTINGS`] are strings, anything else is JSON when it's valid JSON (e.g.
+/// `NVIM_BOT_GIF_FALLBACK='["giphy"]'`) and a string otherwise.
+fn env_settings(vars: impl IntoIterator<Item = (String, String)>) -> Vec<(String, String, Json)> {
+    let mut settings: Vec<(String, String, Json)> = vars
+        .into_iter()
+        .filter_map(|(var, value)| {
+            let key = match LEGACY_ENV_VARS.iter().find(|(legacy, _)| *legacy == var) {
+                Some((_, key)) => key.to_string(),
+                None => var.strip_prefix(ENV_PREFIX)?.to_lowercase(),
+            };
+            // That's where the config file is, which isn't a setting
+            if key == "config" {
+                return None;
+            }
+            let value = if STRING_SETTINGS.contains(&key.as_str()) {
+                Json::String(value)
+            } else {
+                serde_json::from_str(&value).unwrap_or(Json::String(value))
+            };
+            Some((key, var, value))
+        })
+        .collect();
+    // The new variables win over the legacy ones
+    settings.sort_by_key(|(_, var, _)| var.starts_with(ENV_PREFIX));
+    settings
+}
+
+/// A username and password to log in with.
 #[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(std::io::Error),
+    /// There's no `MATRIX_USERNAME` and `MATRIX_PASSWORD` or `credentials_file` to log in with
+    NoCredentials,
+    /// Something's wrong with the setting at `key`, like `rooms.!abc:matrix.org.gifs`
+    Invalid {
+        key: String,
+        message: String,
+    },
+}
+
+impl ConfigError {
+    /// An error for a setting in the part of the config at `prefix`.
+    fn invalid(prefix: &str, err: SettingError) -> Self {
+        let path = err.path().to_string();
+        let key = match (prefix, path.as_str()) {
+            (prefix, ".") => prefix.to_string(),
+            ("", path) => path.to_string(),
+            (prefix, path) => format!("{}.{}", prefix, path),
+        };
+        ConfigError::Invalid {
+            key,
+            message: err.into_inner().to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(err) => write!(f, "{}", err),
+            ConfigError::NoCredentials => write!(
+                f,
+                "no credentials, set MATRIX_USERNAME and MATRIX_PASSWORD or credentials_file"
+            ),
+            ConfigError::Invalid { key, message } if key.is_empty() => write!(f, "{}", message),
+            ConfigError::Invalid { key, message } => write!(f, "{}: {}", key, message),
+        }
+    }
+}
+
+impl From<std::io::Error> for ConfigError {
+    fn from(err: std::io::Error) -> Self {
+        ConfigError::Io(err)
+    }
+}
+
+fn check_gif_provider<E: serde::de::Error>(name: &str) -> Result<(), E> {
+    if PROVIDER_NAMES.contains(&name) {
+        Ok(())
+    } else {
+        Err(E::custom(format!(
+            "unknown gif provider `{}`, expected one of {}",
+            name,
+            PROVIDER_NAMES.join(", ")
+        )))
+    }
+}
+
+fn gif_provider<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    let name = String::deserialize(deserializer)?;
+    check_gif_provider(&name)?;
+    Ok(name)
+}
+
+fn optional_gif_provider<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<String>, D::Error> {
+    let name = Option::<String>::deserialize(deserializer)?;
+    if let Some(name) = &name {
+        check_gif_provider(name)?;
+    }
+    Ok(name)
+}
+
+fn gif_providers<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    let names = Vec::<String>::deserialize(deserializer)?;
+    for name in &names {
+        check_gif_provider(name)?;
+    }
+    Ok(names)
+}
+
+/// Settings for a room. Anything a room doesn't set comes from `room_defaults`.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct RoomConfig {
     pub gifs: bool,
     pub sandwich: bool,
+    /// Overrides the global command prefix for this room
+    pub prefix: Option<String>,
+    /// Whether to redact our replies to messages that get redacted
+    pub redact_replies: bool,
+    /// Overrides the global default gif provider for this room
+    #[serde(deserialize_with = "optional_gif_provider")]
+    pub gif_provider: Option<String>,
+    /// The most mature gifs can be in this room
+    pub gif_rating: Rating,
+    /// Gif searches that are never done in this room, on top of the global ones
+    pub gif_blocklist: Vec<String>,
+    /// Send gifs as mp4 videos when the provider has them, which are a lot smaller than the
+    /// actual gifs
+    pub gif_video: bool,
+    /// How long (in seconds) someone has to wait between uses of a command, by command name
+    /// (`help`, `sandwich`, `url` or `gif`)
+    pub cooldowns: HashMap<String, u64>,
+    /// Whether to tell people when they're using commands too often, instead of just ignoring
+    /// them
+    pub throttle_replies: bool,
+}
+
+impl Default for RoomConfig {
+    fn default() -> Self {
+        Self {
+            gifs: false,
+            sandwich: false,
+            prefix: None,
+            redact_replies: true,
+            gif_provider: None,
+            gif_rating: Rating::default(),
+            gif_blocklist: vec![],
+            gif_video: false,
+            cooldowns: default_cooldowns(),
+            throttle_replies: true,
+        }
+    }
 }
 
-#[derive(Default, Deserialize)]
+impl RoomConfig {
+    /// Changes the settings in `settings`, which are in the same format as the config file.
+    /// Nothing changes if any of them are invalid.
+    pub fn apply(&mut self, settings: &serde_json::Map<String, Json>) -> Result<(), SettingError> {
+        let mut json = serde_json::to_value(&*self).expect("room configs are always valid JSON");
+        for (key, value) in settings {
+            json[key] = value.clone();
+        }
+        *self = serde_path_to_error::deserialize(json)?;
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
-    pub rooms: HashMap<RoomId, RoomConfig>,
+    /// The homeserver to log in to
+    #[serde(default = "default_homeserver")]
+    pub homeserver: String,
+    /// File with the `username` and `password` to log in with, for when they aren't in
+    /// `MATRIX_USERNAME` and `MATRIX_PASSWORD`
+    pub credentials_file: Option<String>,
+    /// API key for the `tenor` gif provider, which is only set up when there is one
+    pub tenor_api_key: Option<String>,
+    /// API key for the `giphy` gif provider, which is only set up when there is one
+    pub giphy_api_key: Option<String>,
+    /// What commands have to start with, e.g. the `!` in `!gif`
+    #[serde(default = "default_prefix")]
+    pub prefix: String,
+    /// Reactions that make us redact the message they're on, when they're from whoever asked
+    /// for that message (or a moderator)
+    #[serde(default = "default_delete_reactions")]
+    pub delete_reactions: Vec<String>,
+    /// Reaction that makes us replace one of our gifs with another one from the same search,
+    /// when it's from whoever asked for the gif
+    #[serde(default = "default_reroll_reaction")]
+    pub reroll_reaction: String,
+    /// Where the bot keeps the stuff it needs to remember between runs
+    #[serde(default = "default_state_dir")]
+    pub state_dir: String,
+    /// How long (in seconds) to reuse media we've uploaded before uploading it again
+    #[serde(default = "default_media_cache_ttl")]
+    pub media_cache_ttl: u64,
+    /// How to upload media, `direct` (the default) or `async` for homeservers that support
+    /// asynchronous uploads
+    #[serde(default)]
+    pub media_upload: UploadMode,
+    /// The biggest gif (in bytes) we'll download and send, which gets lowered to the
+    /// homeserver's upload limit if that's smaller. Smaller versions of bigger gifs are sent
+    /// instead, when the provider has them.
+    #[serde(default = "default_max_gif_size")]
+    pub max_gif_size: u64,
+    /// Directory with a library of gifs for the `local` gif provider, see `gif/local.rs`
+    pub local_gifs: Option<String>,
+    /// Which gif provider `!gif` searches, e.g. `tenor`, `giphy` or `local`. Set this to
+    /// `local` to prefer the local library, falling back to the others when it has nothing.
+    #[serde(default = "default_gif_provider", deserialize_with = "gif_provider")]
+    pub gif_provider: String,
+    /// Gif providers to try, in order, when the one asked for isn't set up, fails or doesn't
+    /// find anything
+    #[serde(default = "default_gif_fallback", deserialize_with = "gif_providers")]
+    pub gif_fallback: Vec<String>,
+    /// Gif searches that are never done, in any room. Single words block searches containing
+    /// that word, anything longer blocks searches containing the whole phrase.
+    #[serde(default)]
+    pub gif_blocklist: Vec<String>,
+    /// Power level people need to change room settings with `!config`
+    #[serde(default = "default_config_power_level")]
+    pub config_power_level: i64,
+    /// Most gifs fetched per minute, across every room
+    #[serde(default = "default_gif_fetches_per_minute")]
+    pub gif_fetches_per_minute: usize,
+    /// Whether to set our presence to offline when we're stopped, so we don't look online
+    /// until the homeserver notices we're gone
+    #[serde(default)]
+    pub offline_on_exit: bool,
+    /// Settings for every room, which the ones in `rooms` can override
+    #[serde(default)]
+    pub room_defaults: RoomConfig,
+    /// What each room in the config file overrides from `room_defaults`, by room ID or alias
+    #[serde(default, rename = "rooms")]
+    room_overrides: HashMap<RoomId, serde_json::Map<String, Json>>,
+    /// The room ID each alias in `rooms` goes to, see [`Config::set_aliases`]
+    #[serde(skip)]
+    aliases: HashMap<String, RoomId>,
+    /// The actual config for each room that has one, by room ID, see [`Config::room`]
+    #[serde(skip)]
+    rooms: HashMap<RoomId, RoomConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            homeserver: default_homeserver(),
+            credentials_file: None,
+            tenor_api_key: None,
+            giphy_api_key: None,
+            prefix: default_prefix(),
+            delete_reactions: default_delete_reactions(),
+            reroll_reaction: default_reroll_reaction(),
+            state_dir: default_state_dir(),
+            media_cache_ttl: default_media_cache_ttl(),
+            media_upload: UploadMode::default(),
+            max_gif_size: default_max_gif_size(),
+            local_gifs: None,
+            gif_provider: default_gif_provider(),
+            gif_fallback: default_gif_fallback(),
+            gif_blocklist: vec![],
+            config_power_level: default_config_power_level(),
+            gif_fetches_per_minute: default_gif_fetches_per_minute(),
+            offline_on_exit: false,
+            room_defaults: RoomConfig::default(),
+            room_overrides: HashMap::new(),
+            aliases: HashMap::new(),
+            rooms: HashMap::new(),
+        }
+    }
 }
 
 impl Config {
-    pub fn from_file(filepath: &str) -> Result<Self, serde_json::Error> {
-        let contents = std::fs::read_to_string(filepath).unwrap();
-        serde_json::from_str(&contents)
+    /// The config for a room: whatever the config file has for it on top of `room_defaults`,
+    /// with any settings changed with `!config` on top of that.
+    pub fn room(&self, room_id: &str) -> &RoomConfig {
+        self.rooms.get(room_id).unwrap_or(&self.room_defaults)
+    }
+
+    /// The config a room would have with `settings` on top of what the config file says.
+    pub fn room_with_settings(
+        &self,
+        room_id: &str,
+        settings: &serde_json::Map<String, Json>,
+    ) -> Result<RoomConfig, SettingError> {
+        let mut room = self.room_defaults.clone();
+        // Aliases first, so the room's ID wins when it's in the config both ways
+        let mut aliases: Vec<&String> = self
+            .aliases
+            .iter()
+            .filter(|(_, alias_room_id)| *alias_room_id == room_id)
+            .map(|(alias, _)| alias)
+            .collect();
+        aliases.sort_unstable();
+        for alias in aliases {
+            if let Some(overrides) = self.room_overrides.get(alias) {
+                room.apply(overrides)?;
+            }
+        }
+        if let Some(overrides) = self.room_overrides.get(room_id) {
+            room.apply(overrides)?;
+        }
+        room.apply(settings)?;
+        Ok(room)
+    }
+
+    /// The room aliases (like `#neovim:matrix.org`) used in `rooms`, which have to be resolved
+    /// to room IDs before their settings apply.
+    pub fn room_aliases(&self) -> impl Iterator<Item = &str> {
+        self.room_overrides
+            .keys()
+            .map(String::as_str)
+            .filter(|room| room.starts_with('#'))
+    }
+
+    /// The room ID an alias in `rooms` was last resolved to.
+    pub fn alias_room_id(&self, alias: &str) -> Option<&str> {
+        self.aliases.get(alias).map(String::as_str)
+    }
+
+    /// Sets what the aliases in `rooms` resolve to, rebuilding the config for every room in
+    /// the config file. Settings changed with `!config` have to be set again after this.
+    pub fn set_aliases(&mut self, aliases: HashMap<String, RoomId>) {
+        self.aliases = aliases;
+        self.rooms.clear();
+
+        let room_ids: HashSet<RoomId> = self
+            .room_overrides
+            .keys()
+            .filter(|room| room.starts_with('!'))
+            .chain(self.aliases.values())
+            .cloned()
+            .collect();
+        let no_settings = serde_json::Map::new();
+        for room_id in room_ids {
+            // Every room's overrides were checked when the config was parsed
+            if let Ok(room) = self.room_with_settings(&room_id, &no_settings) {
+                self.rooms.insert(room_id, room);
+            }
+        }
+    }
+
+    /// Replaces the settings changed with `!config` for a room.
+    pub fn set_room_settings(
+        &mut self,
+        room_id: &str,
+        settings: &serde_json::Map<String, Json>,
+    ) -> Result<(), SettingError> {
+        let room = self.room_with_settings(room_id, settings)?;
+        self.rooms.insert(room_id.to_string(), room);
+        Ok(())
+    }
+
+    /// The command prefix to use in the given room.
+    pub fn prefix(&self, room_id: &str) -> &str {
+        self.room(room_id).prefix.as_deref().unwrap_or(&self.prefix)
+    }
+
+    /// Whether to redact our replies to redacted messages in the given room.
+    pub fn redact_replies(&self, room_id: &str) -> bool {
+        self.room(room_id).redact_replies
+    }
+
+    /// Whether a reaction with `key` should make us redact our message.
+    pub fn is_delete_reaction(&self, key: &str) -> bool {
+        self.delete_reactions
+            .iter()
+            .any(|reaction| same_emoji(reaction, key))
+    }
+
+    /// Whether a reaction with `key` should make us reroll our gif.
+    pub fn is_reroll_reaction(&self, key: &str) -> bool {
+        same_emoji(&self.reroll_reaction, key)
+    }
+
+    /// The gif providers to search in the given room, in order: the one asked for (if any),
+    /// then the room's default, then the fallbacks.
+    pub fn gif_providers<'a>(&'a self, room_id: &str, requested: Option<&'a str>) -> Vec<&'a str> {
+        let default = self
+            .room(room_id)
+            .gif_provider
+            .as_deref()
+            .unwrap_or(&self.gif_provider);
+
+        let mut providers: Vec<&str> = vec![];
+        for provider in requested
+            .into_iter()
+            .chain([default])
+            .chain(self.gif_fallback.iter().map(String::as_str))
+        {
+            if !providers.contains(&provider) {
+                providers.push(provider);
+            }
+        }
+        providers
+    }
+
+    /// The gif rating to use in the given room.
+    pub fn gif_rating(&self, room_id: &str) -> Rating {
+        self.room(room_id).gif_rating
+    }
+
+    /// Whether `command` does anything in the given room, since some have to be turned on.
+    pub fn command_enabled(&self, room_id: &str, command: &str) -> bool {
+        match command {
+            "gif" => self.room(room_id).gifs,
+            "sandwich" => self.room(room_id).sandwich,
+            _ => true,
+        }
+    }
+
+    /// How long someone has to wait between uses of `command` in the given room.
+    pub fn cooldown(&self, room_id: &str, command: &str) -> Duration {
+        let seconds = self.room(room_id).cooldowns.get(command).copied();
+        Duration::from_secs(seconds.unwrap_or(0))
+    }
+
+    /// Whether to reply to commands that are being used too often in the given room.
+    pub fn throttle_replies(&self, room_id: &str) -> bool {
+        self.room(room_id).throttle_replies
+    }
+
+    /// Whether to send gifs as videos in the given room.
+    pub fn gif_video(&self, room_id: &str) -> bool {
+        self.room(room_id).gif_video
+    }
+
+    /// Whether a gif search for `query` is blocked in the given room.
+    pub fn is_blocked_gif_search(&self, room_id: &str, query: &str) -> bool {
+        let query = query.to_lowercase();
+        let words: Vec<&str> = query
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .collect();
+
+        self.gif_blocklist
+            .iter()
+            .chain(&self.room(room_id).gif_blocklist)
+            .map(|term| term.trim().to_lowercase())
+            .any(|term| {
+                if term.contains(char::is_whitespace) {
+                    query.contains(&term)
+                } else {
+                    words.contains(&term.as_str())
+                }
+            })
+    }
+
+    /// Parses a config, with settings from the environment variables in `env` on top of it,
+    /// making sure every setting (including the ones for each room) is valid.
+    pub fn parse(
+        contents: &str,
+        format: Format,
+        env: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self, ConfigError> {
+        let mut json = format.read(contents)?;
+        let Json::Object(settings) = &mut json else {
+            return Err(ConfigError::Invalid {
+                key: String::new(),
+                message: "the config has to be a table of settings".to_string(),
+            });
+        };
+        let env = env_settings(env);
+        for (key, _, value) in &env {
+            settings.insert(key.clone(), value.clone());
+        }
+
+        let mut config: Self = serde_path_to_error::deserialize(json).map_err(|err| {
+            let mut err = ConfigError::invalid("", err);
+            // Point at the environment variable when that's where the setting came from
+            if let ConfigError::Invalid { key, .. } = &mut err {
+                let top = key.split('.').next().unwrap_or_default();
+                if let Some((_, var, _)) = env.iter().rev().find(|(setting, _, _)| setting == top) {
+                    *key = format!("{} (from {})", key, var);
+                }
+            }
+            err
+        })?;
+
+        for (room_id, overrides) in &config.room_overrides {
+            let key = format!("rooms.{}", room_id);
+            if !(room_id.starts_with('!') || room_id.starts_with('#')) || !room_id.contains(':') {
+                return Err(ConfigError::Invalid {
+                    key,
+                    message:
+                        "rooms go by ID (like !abc:matrix.org) or alias (like #neovim:matrix.org)"
+                            .to_string(),
+                });
+            }
+            config
+                .room_defaults
+                .clone()
+                .apply(overrides)
+                .map_err(|err| ConfigError::invalid(&key, err))?;
+        }
+        // Rooms that go by alias get their config once the aliases are resolved
+        config.set_aliases(HashMap::new());
+
+        Ok(config)
+    }
+
+    /// Loads the config from `path` (or just the defaults if there isn't one), with any
+    /// settings from environment variables on top.
+    pub fn load(path: Option<&Path>) -> Result<Self, ConfigError> {
+        let (contents, format) = match path {
+            Some(path) => (std::fs::read_to_string(path)?, Format::of(path)),
+            None => ("{}".to_string(), Format::Json),
+        };
+        let env = std::env::vars_os()
+            .filter_map(|(var, value)| Some((var.into_string().ok()?, value.into_string().ok()?)));
+        Self::parse(&contents, format, env)
+    }
+
+    /// The username and password to log in with, from `MATRIX_USERNAME` and `MATRIX_PASSWORD`
+    /// or else `credentials_file`.
+    pub fn credentials(&self) -> Result<Credentials, ConfigError> {
+        if let (Ok(username), Ok(password)) = (
+            std::env::var("MATRIX_USERNAME"),
+            std::env::var("MATRIX_PASSWORD"),
+        ) {
+            return Ok(Credentials { username, password });
+        }
+
+        let Some(path) = &self.credentials_file else {
+            return Err(ConfigError::NoCredentials);
+        };
+        let contents = std::fs::read_to_string(path)?;
+        let json = Format::of(Path::new(path)).read(&contents)?;
+        serde_path_to_error::deserialize(json).map_err(|err| ConfigError::invalid(path, err))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn set(config: &mut RoomConfig, key: &str, value: &str) -> Result<(), String> {
+        let value = setting_value(key, value)?;
+        config
+            .apply(&serde_json::Map::from_iter([(key.to_string(), value)]))
+            .map_err(|err| err.to_string())
+    }
+
+    #[test]
+    fn room_settings_work() {
+        let mut config = RoomConfig::default();
+        assert!(set(&mut config, "gifs", "on").is_ok());
+        assert!(set(&mut config, "prefix", "?").is_ok());
+        assert!(set(&mut config, "gif_rating", "pg-13").is_ok());
+        assert!(set(&mut config, "gifs", "maybe").is_err());
+        assert!(set(&mut config, "gif_rating", "nc-17").is_err());
+        assert!(set(&mut config, "cooldowns", "0").is_err());
+        assert!(config.gifs);
+        assert_eq!(config.prefix.as_deref(), Some("?"));
+        assert_eq!(config.gif_rating, Rating::Pg13);
+
+        assert!(set(&mut config, "prefix", "default").is_ok());
+        assert_eq!(config.prefix, None);
+        assert!(set(&mut config, "gif_provider", "myspace").is_err());
+    }
+
+    #[test]
+    fn configs_work() {
+        let config = Config::parse(
+            r#"{
+                "room_defaults": { "gifs": true },
+                "rooms": {
+                    "!a:matrix.org": { "sandwich": true },
+                    "!b:matrix.org": { "gifs": false, "prefix": "?" }
+                }
+            }"#,
+            Format::Json,
+            [],
+        )
+        .unwrap();
+        assert!(config.room("!a:matrix.org").gifs);
+        assert!(config.room("!a:matrix.org").sandwich);
+        assert!(!config.room("!b:matrix.org").gifs);
+        assert_eq!(config.prefix("!b:matrix.org"), "?");
+        // Rooms that aren't in the config get the defaults
+        assert!(config.room("!c:matrix.org").gifs);
+        assert_eq!(config.prefix("!c:matrix.org"), "!");
+
+        let error = |contents| {
+            Config::parse(contents, Format::Json, [])
+                .err()
+                .unwrap()
+                .to_string()
+        };
+        assert!(error(r#"{ "prefx": "?" }"#).starts_with("prefx: unknown field `prefx`"));
+        assert!(error(r#"{ "gif_fallback": ["tenor", "myspace"] }"#)
+            .starts_with("gif_fallback: unknown gif provider `myspace`"));
+        assert!(
+            error(r#"{ "rooms": { "!a:matrix.org": { "gifs": "yes" } } }"#)
+                .starts_with("rooms.!a:matrix.org.gifs: invalid type")
+        );
+        assert!(
+            error(r#"{ "rooms": { "!a:matrix.org": { "gif": true } } }"#)
+                .starts_with("rooms.!a:matrix.org.gif: unknown field `gif`")
+        );
+        assert!(error(r#"{ "rooms": { "neovim": {} } }"#).starts_with("rooms.neovim: rooms go by"));
+    }
+
+    #[test]
+    fn aliases_work() {
+        let mut config = Config::parse(
+            r##"{
+                "rooms": {
+                    "#neovim:matrix.org": { "gifs": true, "prefix": "?" },
+                    "!a:matrix.org": { "prefix": "." }
+                }
+            }"##,
+            Format::Json,
+            [],
+        )
+        .unwrap();
+        // Nothing applies until the alias is resolved
+        assert!(!config.room("!a:matrix.org").gifs);
+        assert_eq!(config.prefix("!a:matrix.org"), ".");
+
+        config.set_aliases(HashMap::from([(
+            "#neovim:matrix.org".to_string(),
+            "!a:matrix.org".to_string(),
+        )]));
+        assert_eq!(
+            config.alias_room_id("#neovim:matrix.org"),
+            Some("!a:matrix.org")
+        );
+        assert!(config.room("!a:matrix.org").gifs);
+        // The room ID wins over the alias
+        assert_eq!(config.prefix("!a:matrix.org"), ".");
+
+        config.set_aliases(HashMap::from([(
+            "#neovim:matrix.org".to_string(),
+            "!b:matrix.org".to_string(),
+        )]));
+        assert!(!config.room("!a:matrix.org").gifs);
+        assert!(config.room("!b:matrix.org").gifs);
+        assert_eq!(config.prefix("!b:matrix.org"), "?");
+
+        // Aliases that aren't in the config don't do anything
+        config.set_aliases(HashMap::from([(
+            "#elsewhere:matrix.org".to_string(),
+            "!c:matrix.org".to_string(),
+        )]));
+        assert_eq!(config.prefix("!c:matrix.org"), "!");
+    }
+
+    #[test]
+    fn toml_and_env_work() {
+        let env = |vars: &[(&str, &str)]| {
+            vars.iter()
+                .map(|(var, value)| (var.to_string(), value.to_string()))
+                .collect::<Vec<_>>()
+        };
+
+        let config = Config::parse(
+            r##"
+                state_dir = "/var/lib/bot"
+                gif_fallback = ["local"]
+
+                [room_defaults]
+                gifs = true
+
+                [rooms."!a:matrix.org"]
+                cooldowns = { gif = 30 }
+            "##,
+            Format::Toml,
+            env(&[
+                ("MATRIX_HOMESERVER", "https://old.example.org"),
+                ("NVIM_BOT_HOMESERVER", "https://example.org"),
+                ("TENOR_API_KEY", "123456"),
+                ("GIPHY_API_KEY", "null"),
+                ("NVIM_BOT_GIF_FALLBACK", r#"["giphy"]"#),
+                ("NVIM_BOT_CONFIG", "bot.toml"),
+                ("HOME", "/root"),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(config.state_dir, "/var/lib/bot");
+        assert_eq!(config.homeserver, "https://example.org");
+        assert_eq!(config.tenor_api_key.as_deref(), Some("123456"));
+        assert_eq!(config.giphy_api_key.as_deref(), Some("null"));
+        assert_eq!(config.gif_fallback, vec!["giphy"]);
+        assert!(config.room("!a:matrix.org").gifs);
+        assert_eq!(
+            config.cooldown("!a:matrix.org", "gif"),
+            Duration::from_secs(30)
+        );
+
+        let error = Config::parse("", Format::Toml, env(&[("NVIM_BOT_MAX_GIF_SIZE", "big")]));
+        assert!(error
+            .err()
+            .unwrap()
+            .to_string()
+            .starts_with("max_gif_size (from NVIM_BOT_MAX_GIF_SIZE): invalid type"));
     }
 }
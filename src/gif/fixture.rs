@@ -0,0 +1,96 @@
+//! A stand-in for the real providers in the REPL, so `!gif` works without the network. Every
+//! search finds the same made-up gif.
+
+use std::path::{Path, PathBuf};
+
+use super::{Gif, GifProvider, Rating, Rendition, SearchError, Source};
+
+const SIZE: u16 = 16;
+
+pub struct Fixture {
+    /// The provider this stands in for
+    name: &'static str,
+    path: PathBuf,
+}
+
+impl Fixture {
+    /// Stands in for the provider called `name`, keeping its gif in `dir`.
+    pub fn new(name: &'static str, dir: &Path) -> std::io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let path = dir.join(format!("{}.gif", name));
+
+        let mut bytes = vec![];
+        let mut encoder =
+            ::gif::Encoder::new(&mut bytes, SIZE, SIZE, &[]).map_err(std::io::Error::other)?;
+        let mut pixels = [0x80; SIZE as usize * SIZE as usize * 4];
+        encoder
+            .write_frame(&::gif::Frame::from_rgba(SIZE, SIZE, &mut pixels))
+            .map_err(std::io::Error::other)?;
+        drop(encoder);
+        std::fs::write(&path, bytes)?;
+
+        Ok(Self { name, path })
+    }
+}
+
+impl GifProvider for Fixture {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn attribution(&self) -> &'static str {
+        "Made up by the REPL, nothing was searched"
+    }
+
+    fn rating(&self, _rating: Rating) -> &'static str {
+        ""
+    }
+
+    fn search(
+        &self,
+        _agent: &ureq::Agent,
+        _query: &str,
+        _rating: Rating,
+    ) -> Result<Vec<Gif>, SearchError> {
+        Ok(vec![Gif {
+            rendition: Rendition {
+                source: Source::File(self.path.clone()),
+                height: SIZE.into(),
+                width: SIZE.into(),
+                size: std::fs::metadata(&self.path)?.len() as i32,
+                mimetype: "image/gif".to_string(),
+                duration: None,
+            },
+            smaller: vec![],
+            videos: vec![],
+            preview: None,
+            attribution: self.attribution(),
+        }])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::media;
+
+    #[test]
+    fn fixture_works() {
+        let dir = std::env::temp_dir().join(format!("fixture-test-{}", std::process::id()));
+        let fixture = Fixture::new("tenor", &dir).unwrap();
+        let gifs = fixture
+            .search(&ureq::agent(), "anything", Rating::G)
+            .unwrap();
+        let bytes = std::fs::read(dir.join("tenor.gif")).unwrap();
+        _ = std::fs::remove_dir_all(&dir);
+
+        assert_eq!(fixture.name(), "tenor");
+        assert_eq!(gifs.len(), 1);
+        assert_eq!(
+            gifs[0].rendition.source,
+            Source::File(dir.join("tenor.gif"))
+        );
+        assert_eq!(gifs[0].rendition.size as usize, bytes.len());
+        assert_eq!(media::sniff(&bytes).unwrap().mimetype, "image/gif");
+    }
+}
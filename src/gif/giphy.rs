@@ -0,0 +1,131 @@
+//! [Giphy](https://giphy.com), used for `!giphy`.
+
+#![allow(unused)] // just cuz we have JSON deserialized stuff that we don't all use
+use serde::Deserialize;
+
+use super::{Gif, GifProvider, Rating, Rendition, SearchError, Source, BATCH_SIZE};
+
+#[derive(Debug, Deserialize)]
+struct GiphyMeta {
+    status: i32,
+    msg: String,
+    response_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct GiphyResponse {
+    data: Vec<GiphyResponseData>,
+    meta: GiphyMeta,
+}
+
+#[derive(Debug, Deserialize)]
+struct GiphyImageData {
+    height: String,
+    width: String,
+    size: String,
+    url: String,
+    /// Only some renditions have mp4 versions
+    mp4: Option<String>,
+    mp4_size: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GiphyImages {
+    original: GiphyImageData,
+    preview_gif: GiphyImageData,
+    #[serde(rename = "480w_still")]
+    foureightyw_still: GiphyImageData,
+}
+
+#[derive(Debug, Deserialize)]
+struct GiphyResponseData {
+    #[serde(rename = "type")]
+    t: String,
+    images: GiphyImages,
+    url: String,
+    source: String,
+    title: String,
+}
+
+pub struct Giphy {
+    api_key: String,
+}
+
+impl Giphy {
+    pub fn new(api_key: String) -> Self {
+        Self { api_key }
+    }
+}
+
+impl GifProvider for Giphy {
+    fn name(&self) -> &'static str {
+        "giphy"
+    }
+
+    fn attribution(&self) -> &'static str {
+        "Powered by GIPHY"
+    }
+
+    fn rating(&self, rating: Rating) -> &'static str {
+        match rating {
+            Rating::G => "g",
+            Rating::Pg => "pg",
+            Rating::Pg13 => "pg-13",
+            Rating::R => "r",
+        }
+    }
+
+    fn search(
+        &self,
+        agent: &ureq::Agent,
+        query: &str,
+        rating: Rating,
+    ) -> Result<Vec<Gif>, SearchError> {
+        let response = agent
+            .get("https://api.giphy.com/v1/gifs/search")
+            .set("Accept", "application/json")
+            .set("Content-Type", "application/json")
+            .set("Charset", "utf-8")
+            .query("api_key", &self.api_key)
+            .query("q", query)
+            .query("limit", &BATCH_SIZE.to_string())
+            .query("rating", self.rating(rating))
+            .call()?;
+
+        let response: GiphyResponse = serde_json::de::from_reader(&mut response.into_reader())?;
+        Ok(response
+            .data
+            .iter()
+            .filter_map(|gif| {
+                let rendition = |image: &GiphyImageData, mimetype: &str| {
+                    Some(Rendition {
+                        source: Source::Url(image.url.to_owned()),
+                        width: image.width.parse().ok()?,
+                        height: image.height.parse().ok()?,
+                        size: image.size.parse().ok()?,
+                        mimetype: mimetype.to_owned(),
+                        duration: None,
+                    })
+                };
+                let video = |image: &GiphyImageData| {
+                    Some(Rendition {
+                        source: Source::Url(image.mp4.clone()?),
+                        size: image.mp4_size.as_ref()?.parse().ok()?,
+                        mimetype: "video/mp4".to_owned(),
+                        ..rendition(image, "")?
+                    })
+                };
+
+                Some(Gif {
+                    rendition: rendition(&gif.images.original, "image/gif")?,
+                    smaller: rendition(&gif.images.preview_gif, "image/gif")
+                        .into_iter()
+                        .collect(),
+                    videos: video(&gif.images.original).into_iter().collect(),
+                    preview: Some(rendition(&gif.images.foureightyw_still, "image/jpeg")?),
+                    attribution: self.attribution(),
+                })
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,106 @@
+//! A local library of curated gifs (or stickers), for stuff like `!gif shipit`.
+//!
+//! The library is a directory with a `manifest.json` in it mapping keywords to files in that
+//! directory, like `{ "shipit": ["shipit.gif", "ship-it.webp"] }`.
+
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use super::{Gif, GifProvider, Rating, Rendition, SearchError, Source};
+use crate::media;
+
+pub struct Local {
+    dir: PathBuf,
+    /// Keyword (lowercase) to files with that keyword
+    manifest: HashMap<String, Vec<String>>,
+}
+
+impl Local {
+    pub fn open(dir: &Path) -> std::io::Result<Self> {
+        let contents = std::fs::read_to_string(dir.join("manifest.json"))?;
+        let manifest: HashMap<String, Vec<String>> = serde_json::from_str(&contents)?;
+
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            manifest: manifest
+                .into_iter()
+                .map(|(keyword, files)| (keyword.to_lowercase(), files))
+                .collect(),
+        })
+    }
+
+    fn gif(&self, file: &str) -> Result<Gif, SearchError> {
+        let path = self.dir.join(file);
+        let mut header = vec![];
+        std::fs::File::open(&path)?
+            .take(32)
+            .read_to_end(&mut header)?;
+
+        let info = media::sniff(&header).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{} isn't a gif, png or webp", path.display()),
+            )
+        })?;
+
+        Ok(Gif {
+            rendition: Rendition {
+                size: std::fs::metadata(&path)?.len() as i32,
+                source: Source::File(path),
+                height: info.height,
+                width: info.width,
+                mimetype: info.mimetype.to_string(),
+                duration: None,
+            },
+            smaller: vec![],
+            videos: vec![],
+            preview: None,
+            attribution: self.attribution(),
+        })
+    }
+}
+
+impl GifProvider for Local {
+    fn name(&self) -> &'static str {
+        "local"
+    }
+
+    fn attribution(&self) -> &'static str {
+        "From our gif library"
+    }
+
+    fn rating(&self, _rating: Rating) -> &'static str {
+        // Everything in the library was put there on purpose, so there's nothing to filter
+        ""
+    }
+
+    fn search(
+        &self,
+        _agent: &ureq::Agent,
+        query: &str,
+        _rating: Rating,
+    ) -> Result<Vec<Gif>, SearchError> {
+        let query = query.to_lowercase();
+        let words: Vec<&str> = query.split_whitespace().collect();
+
+        let mut files: Vec<&str> = vec![];
+        for (keyword, keyword_files) in &self.manifest {
+            if *keyword == query || words.contains(&keyword.as_str()) {
+                files.extend(keyword_files.iter().map(String::as_str));
+            }
+        }
+        // The manifest is a HashMap, so keep results in a consistent order
+        files.sort_unstable();
+        files.dedup();
+
+        Ok(files
+            .into_iter()
+            .filter_map(|file| {
+                self.gif(file)
+                    .map_err(|err| eprintln!("Skipping local gif {}: {}", file, err))
+                    .ok()
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,217 @@
+//! Gif search. Each provider (Tenor, Giphy, ...) lives in its own module and implements
+//! [`GifProvider`], and [`Providers`] searches them in whatever order the config asks for.
+
+mod fixture;
+mod giphy;
+mod local;
+mod tenor;
+
+pub use fixture::Fixture;
+pub use giphy::Giphy;
+pub use local::Local;
+pub use tenor::Tenor;
+
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::path::PathBuf;
+
+/// What each provider is called in the config
+pub const PROVIDER_NAMES: &[&str] = &["tenor", "giphy", "local"];
+
+/// How many gifs to ask for per search, which is what rerolling picks from
+const BATCH_SIZE: usize = 10;
+
+/// How family-friendly gifs have to be, using the usual movie ratings.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Rating {
+    G,
+    #[default]
+    Pg,
+    #[serde(rename = "pg-13")]
+    Pg13,
+    R,
+}
+
+#[derive(Debug)]
+pub enum SearchError {
+    Ureq(Box<ureq::Error>),
+    Json(serde_json::Error),
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for SearchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SearchError::Ureq(err) => write!(f, "{}", err),
+            SearchError::Json(err) => write!(f, "bad response: {}", err),
+            SearchError::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<ureq::Error> for SearchError {
+    fn from(err: ureq::Error) -> Self {
+        SearchError::Ureq(Box::new(err))
+    }
+}
+
+impl From<std::io::Error> for SearchError {
+    fn from(err: std::io::Error) -> Self {
+        SearchError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for SearchError {
+    fn from(err: serde_json::Error) -> Self {
+        SearchError::Json(err)
+    }
+}
+
+pub trait GifProvider {
+    /// What the provider is called in the config, e.g. `tenor`
+    fn name(&self) -> &'static str;
+
+    /// Credit for the provider, shown along with its gifs
+    fn attribution(&self) -> &'static str;
+
+    /// The provider's own value for `rating`, for whatever content filter parameter it has.
+    fn rating(&self, rating: Rating) -> &'static str;
+
+    /// Searches for (up to [`BATCH_SIZE`]) gifs matching `query`.
+    fn search(
+        &self,
+        agent: &ureq::Agent,
+        query: &str,
+        rating: Rating,
+    ) -> Result<Vec<Gif>, SearchError>;
+}
+
+/// Where a gif (or its preview) comes from.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Source {
+    Url(String),
+    File(PathBuf),
+}
+
+/// One version of a gif (or its preview), since providers have them in different sizes.
+#[derive(Clone)]
+pub struct Rendition {
+    pub source: Source,
+    pub height: i32,
+    pub width: i32,
+    pub size: i32,
+    pub mimetype: String,
+    /// How long videos are, in milliseconds
+    pub duration: Option<u64>,
+}
+
+#[derive(Clone)]
+pub struct Gif {
+    pub rendition: Rendition,
+    /// Smaller versions of the gif, biggest first, for when it's too big to send
+    pub smaller: Vec<Rendition>,
+    /// Video versions of the gif, smallest first since they're for rooms that want to save
+    /// data
+    pub videos: Vec<Rendition>,
+    /// Not every provider has previews
+    pub preview: Option<Rendition>,
+    /// Credit for the provider the gif came from
+    pub attribution: &'static str,
+}
+
+impl Gif {
+    /// Every version of the gif we could send, in the order to try them. Videos (smallest
+    /// first) come before everything else when they're wanted.
+    pub fn renditions(&self, video: bool) -> impl Iterator<Item = &Rendition> {
+        let videos = if video { &self.videos[..] } else { &[] };
+        videos
+            .iter()
+            .chain(std::iter::once(&self.rendition))
+            .chain(&self.smaller)
+    }
+}
+
+/// All the gif providers we can search, i.e. the ones that are set up (with an API key or
+/// whatever else they need).
+#[derive(Default)]
+pub struct Providers {
+    providers: Vec<Box<dyn GifProvider>>,
+}
+
+impl Providers {
+    pub fn add(&mut self, provider: Box<dyn GifProvider>) {
+        self.providers.push(provider);
+    }
+
+    fn get(&self, name: &str) -> Option<&dyn GifProvider> {
+        self.providers
+            .iter()
+            .find(|provider| provider.name() == name)
+            .map(|provider| provider.as_ref())
+    }
+
+    /// Searches the providers named in `order`, moving on to the next one whenever a provider
+    /// isn't set up, fails, or doesn't find anything.
+    pub fn search(
+        &self,
+        agent: &ureq::Agent,
+        order: &[&str],
+        query: &str,
+        rating: Rating,
+    ) -> Vec<Gif> {
+        for name in order {
+            let Some(provider) = self.get(name) else {
+                println!(
+                    "Not searching '{}' with {} because it isn't set up",
+                    query, name
+                );
+                continue;
+            };
+
+            match provider.search(agent, query, rating) {
+                Ok(gifs) if !gifs.is_empty() => return gifs,
+                Ok(_) => {}
+                Err(err) => eprintln!("Error searching '{}' with {}: {}", query, name, err),
+            }
+        }
+
+        vec![]
+    }
+}
+
+/// Hands out gifs from a search, starting at a random one and going round-robin from there
+/// each time the gif gets rerolled.
+pub struct Picker {
+    /// What was searched for
+    query: String,
+    gifs: Vec<Gif>,
+    next: usize,
+}
+
+impl Picker {
+    pub fn new(query: &str, gifs: Vec<Gif>) -> Self {
+        let random = RandomState::new().build_hasher().finish() as usize;
+        Self {
+            query: query.to_string(),
+            next: random % gifs.len().max(1),
+            gifs,
+        }
+    }
+
+    pub fn query(&self) -> &str {
+        &self.query
+    }
+
+    /// Whether there's another gif to reroll to.
+    pub fn can_reroll(&self) -> bool {
+        self.gifs.len() > 1
+    }
+
+    pub fn pick(&mut self) -> Option<&Gif> {
+        let gif = self.gifs.get(self.next)?;
+        self.next = (self.next + 1) % self.gifs.len();
+        Some(gif)
+    }
+}
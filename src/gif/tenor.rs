@@ -0,0 +1,210 @@
+//! [Tenor](https://tenor.com), the default gif provider.
+
+#![allow(unused)] // just cuz we have JSON deserialized stuff that we don't all use
+                  // TODO(smolcK): we could do something about that idk, I like having the types
+use serde::Deserialize;
+use std::collections::HashMap;
+
+use super::{Gif, GifProvider, Rating, Rendition, SearchError, Source, BATCH_SIZE};
+
+#[derive(Deserialize)]
+struct Response {
+    next: String,
+    results: Vec<ResponseObject>,
+}
+
+#[derive(Deserialize)]
+struct ResponseObject {
+    created: f32,
+    hasaudio: bool,
+    id: String,
+    media_formats: HashMap<ContentFormat, MediaObject>,
+}
+
+#[derive(Deserialize)]
+struct MediaObject {
+    url: String,
+    dims: [i32; 2], // width, height
+    duration: f32,
+    size: i32,
+}
+
+#[derive(PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+// TODO(smolck): No idea if this is right lol
+enum ContentFormat {
+    // Preview,
+    Gif,
+    #[serde(rename = "gifpreview")]
+    GifPreview,
+
+    MediumGif,
+    #[serde(rename = "mediumgifpreview")]
+    MediumGifPreview,
+
+    TinyGif,
+    #[serde(rename = "tinygifpreview")]
+    TinyGifPreview,
+
+    NanoGif,
+    #[serde(rename = "nanogifpreview")]
+    NanoGifPreview,
+
+    Mp4,
+    #[serde(rename = "mp4preview")]
+    Mp4Preview,
+
+    LoopedMp4,
+    #[serde(rename = "loopedmp4preview")]
+    LoopedMp4Preview,
+
+    TinyMp4,
+    #[serde(rename = "tinymp4preview")]
+    TinyMp4Preview,
+
+    NanoMp4,
+    #[serde(rename = "nanomp4preview")]
+    NanoMp4Preview,
+
+    Webm,
+    #[serde(rename = "webmpreview")]
+    WebmPreview,
+
+    TinyWebm,
+    #[serde(rename = "tinywebmpreview")]
+    TinyWebmPreview,
+
+    NanoWebm,
+    #[serde(rename = "nanowebmpreview")]
+    NanoWebmPreview,
+
+    #[serde(rename = "webp")]
+    Webp,
+
+    #[serde(rename = "webp_transparent")]
+    WebpTransparent,
+    #[serde(rename = "webppreview_transparent")]
+    WebpPreviewTransparent,
+
+    #[serde(rename = "tinywebp_transparent")]
+    TinyWebpTransparent,
+    #[serde(rename = "tinywebppreview_transparent")]
+    TinyWebpPreviewTransparent,
+
+    #[serde(rename = "nanowebp_transparent")]
+    NanoWebpTransparent,
+    #[serde(rename = "nanowebppreview_transparent")]
+    NanoWebpPreviewTransparent,
+
+    #[serde(rename = "gif_transparent")]
+    GifTransparent,
+    #[serde(rename = "gifpreview_transparent")]
+    GifPreviewTransparent,
+
+    #[serde(rename = "tinygif_transparent")]
+    TinyGifTransparent,
+    #[serde(rename = "tinygifpreview_transparent")]
+    TinyGifPreviewTransparent,
+
+    #[serde(rename = "nanogif_transparent")]
+    NanoGifTransparent,
+    #[serde(rename = "nanogifpreview_transparent")]
+    NanoGifPreviewTransparent,
+}
+
+pub struct Tenor {
+    api_key: String,
+}
+
+impl Tenor {
+    pub fn new(api_key: String) -> Self {
+        Self { api_key }
+    }
+}
+
+impl GifProvider for Tenor {
+    fn name(&self) -> &'static str {
+        "tenor"
+    }
+
+    fn attribution(&self) -> &'static str {
+        "Via Tenor"
+    }
+
+    fn rating(&self, rating: Rating) -> &'static str {
+        match rating {
+            Rating::G => "high",
+            Rating::Pg => "medium",
+            Rating::Pg13 => "low",
+            Rating::R => "off",
+        }
+    }
+
+    fn search(
+        &self,
+        agent: &ureq::Agent,
+        query: &str,
+        rating: Rating,
+    ) -> Result<Vec<Gif>, SearchError> {
+        let response = agent
+            .get("https://tenor.googleapis.com/v2/search")
+            .set("Accept", "application/json")
+            .set("Content-Type", "application/json")
+            .set("Charset", "utf-8")
+            // TODO(smolck): I hope this sanitizes this cuz it's user input lol
+            .query("q", query)
+            .query("key", &self.api_key)
+            .query("limit", &BATCH_SIZE.to_string())
+            .query("contentfilter", self.rating(rating))
+            .call()?;
+
+        let response: Response = serde_json::de::from_reader(&mut response.into_reader())?;
+        Ok(response
+            .results
+            .iter()
+            .filter_map(|result| {
+                let rendition = |format| {
+                    let info = result.media_formats.get(&format)?;
+                    let [width, height] = info.dims;
+                    let video = matches!(
+                        format,
+                        ContentFormat::Mp4 | ContentFormat::TinyMp4 | ContentFormat::NanoMp4
+                    );
+                    let mimetype = match format {
+                        _ if video => "video/mp4",
+                        ContentFormat::TinyGifPreview => "image/png",
+                        _ => "image/gif",
+                    };
+
+                    Some(Rendition {
+                        source: Source::Url(info.url.clone()),
+                        width,
+                        height,
+                        size: info.size,
+                        mimetype: mimetype.to_owned(),
+                        duration: video.then_some((info.duration * 1000.0) as u64),
+                    })
+                };
+
+                Some(Gif {
+                    rendition: rendition(ContentFormat::TinyGif)?,
+                    smaller: rendition(ContentFormat::NanoGif).into_iter().collect(),
+                    videos: {
+                        let mut videos: Vec<Rendition> = [
+                            ContentFormat::NanoMp4,
+                            ContentFormat::TinyMp4,
+                            ContentFormat::Mp4,
+                        ]
+                        .into_iter()
+                        .filter_map(rendition)
+                        .collect();
+                        videos.sort_by_key(|video| video.size);
+                        videos
+                    },
+                    preview: Some(rendition(ContentFormat::TinyGifPreview)?),
+                    attribution: self.attribution(),
+                })
+            })
+            .collect())
+    }
+}
@@ -51,6 +51,21 @@ lazy_static::lazy_static! {
     ];
 }
 
+/// A Lua pattern with a NUL after it. lua-patterns is a port of Lua's C code, and like it reads
+/// the byte after the pattern expecting a NUL. Without one that byte is whatever comes next in
+/// memory, and a `*`, `?` or `-` there makes the last item optional, so `v_o` would match `v_O`.
+struct Pattern(String);
+
+impl Pattern {
+    fn new(pattern: &str) -> Self {
+        Self(format!("{}\0", pattern))
+    }
+
+    fn get(&self) -> LuaPattern<'_> {
+        LuaPattern::new(&self.0[..self.0.len() - 1])
+    }
+}
+
 #[derive(Debug)]
 pub struct Patterns {
     escaped: String,
@@ -73,11 +88,11 @@ pub struct Match<'a> {
 fn escape_pattern(text: &str) -> Result<String, LuaPatternError> {
     // gsub_checked in case emojis or something are used which would make this
     // create invalid unicode and crash the program if we used the normal gsub
-    LuaPattern::new("([^%w])").gsub_checked(text, "%%%1")
+    Pattern::new("([^%w])").get().gsub_checked(text, "%%%1")
 }
 
 fn ignorecase_pattern(text: &str) -> String {
-    LuaPattern::new("(%a)").gsub_with(text, |cc| {
+    Pattern::new("(%a)").get().gsub_with(text, |cc| {
         // TODO(smolck): umm . . . what
         format!("[{}{}]", cc.get(1).to_lowercase(), cc.get(1).to_uppercase())
     })
@@ -98,11 +113,78 @@ fn encode_url(name: &str) -> String {
         .collect()
 }
 
+/// Mode prefixes used by help tags, e.g. `i_CTRL-R` or `v_o`.
+const MODE_PREFIXES: [&str; 5] = ["i_", "v_", "c_", "t_", "o_"];
+
+/// Turns a single `<...>` key into the form used by help tags, so `<C-w>` becomes `CTRL-W`
+/// and `<S-a>` becomes `A`. Keys that are already tags on their own (`<C-Left>`, `<Leader>`)
+/// are left alone.
+fn normalize_key(key: &str) -> String {
+    let inner = &key[1..key.len() - 1];
+    let mut chars = inner.chars();
+    let (Some(modifier), Some('-')) = (chars.next(), chars.next()) else {
+        return key.to_string();
+    };
+    let rest = chars.as_str();
+    let single = (rest.chars().count() == 1).then(|| rest.chars().next().unwrap());
+
+    match (modifier.to_ascii_uppercase(), single) {
+        ('C', Some(c)) => format!("CTRL-{}", c.to_ascii_uppercase()),
+        // There's no tag for each individual meta chord, just the docs on META itself
+        ('M' | 'A', Some(_)) => "META".to_string(),
+        ('S', Some(c)) if c.is_ascii_alphabetic() => c.to_ascii_uppercase().to_string(),
+        ('C' | 'M' | 'A' | 'S' | 'D', None) => {
+            format!("<{}-{}>", modifier.to_ascii_uppercase(), rest)
+        }
+        _ => key.to_string(),
+    }
+}
+
+/// Normalizes Vim key notation (`<C-w>v`, `i_<C-r>`, `<C-x><C-n>`) into the `CTRL-X_y` style
+/// that help tags use, keeping any mode prefix. Names without `<...>` keys are returned as is.
+fn normalize_keycodes(name: &str) -> String {
+    let (prefix, keys) = MODE_PREFIXES
+        .iter()
+        .find(|prefix| name.len() > prefix.len() && name.starts_with(*prefix))
+        .map_or(("", name), |prefix| name.split_at(prefix.len()));
+
+    if !keys.contains('<') {
+        return name.to_string();
+    }
+
+    let mut tokens = vec![];
+    let mut rest = keys;
+    while let Some(c) = rest.chars().next() {
+        let end = match rest.find('>') {
+            Some(end) if c == '<' && end > 1 && !rest[1..end].contains('<') => end + 1,
+            _ => c.len_utf8(),
+        };
+        let token = &rest[..end];
+        tokens.push(if end > 1 && token.starts_with('<') {
+            normalize_key(token)
+        } else {
+            token.to_string()
+        });
+        rest = &rest[end..];
+    }
+
+    let mut normalized = prefix.to_string();
+    for (i, token) in tokens.iter().enumerate() {
+        // Tags separate keys with `_` on either side of a CTRL chord, e.g. `CTRL-W_v`,
+        // `[_CTRL-I` and `i_CTRL-X_CTRL-N`, but otherwise just put them next to each other.
+        if i > 0 && (token.starts_with("CTRL-") || tokens[i - 1].starts_with("CTRL-")) {
+            normalized.push('_');
+        }
+        normalized.push_str(token);
+    }
+    normalized
+}
+
 fn generate_search_patterns(name: &str) -> Option<Patterns> {
     let name = if let Some(replacement) = FULL_REPLACEMENTS.get(&name) {
         replacement.to_string()
     } else {
-        let mut name = name.to_string();
+        let mut name = normalize_keycodes(name);
         for r in REPLACEMENTS.iter() {
             let patt = if r.should_escape_pattern {
                 let Ok(escaped) = escape_pattern(r.pattern) else {
@@ -112,7 +194,7 @@ fn generate_search_patterns(name: &str) -> Option<Patterns> {
             } else {
                 r.pattern.to_string()
             };
-            name = LuaPattern::new(&patt).gsub(&name, r.replacement);
+            name = Pattern::new(&patt).get().gsub(&name, r.replacement);
         }
         name
     };
@@ -120,8 +202,8 @@ fn generate_search_patterns(name: &str) -> Option<Patterns> {
     let Ok(escaped) = escape_pattern(&name) else {
         return None;
     };
-    let wildcard = LuaPattern::new("%%%*").gsub(&escaped, ".*");
-    let wildcard = LuaPattern::new("%%%?").gsub(&wildcard, ".");
+    let wildcard = Pattern::new("%%%*").get().gsub(&escaped, ".*");
+    let wildcard = Pattern::new("%%%?").get().gsub(&wildcard, ".");
     Some(Patterns {
         icase: ignorecase_pattern(&escaped),
         // TODO(smolck): Umm . . . what
@@ -135,9 +217,12 @@ fn generate_search_patterns(name: &str) -> Option<Patterns> {
 }
 
 fn find_in_tagfile_and_score(tagfile: &str, patterns: Patterns) -> Vec<Match<'_>> {
-    let mut escaped = LuaPattern::new(&patterns.escaped);
-    let mut icase = LuaPattern::new(&patterns.icase);
-    let mut wildcard = LuaPattern::new(&patterns.wildcard);
+    let (escaped, icase, wildcard) = (
+        Pattern::new(&patterns.escaped),
+        Pattern::new(&patterns.icase),
+        Pattern::new(&patterns.wildcard),
+    );
+    let (mut escaped, mut icase, mut wildcard) = (escaped.get(), icase.get(), wildcard.get());
     let mut matches = Vec::new();
     let mut score = 0;
     let mut add = false;
@@ -183,7 +268,7 @@ fn find_in_tagfile_and_score(tagfile: &str, patterns: Patterns) -> Vec<Match<'_>
 
             // This should always be true maybe? idk
             if let Some(pos) = matchpos {
-                if pos > 1 && LuaPattern::new("^%w%w").matches(&tag[(pos - 1)..]) {
+                if pos > 1 && Pattern::new("^%w%w").get().matches(&tag[(pos - 1)..]) {
                     score += 10_000;
                 } else if pos > 3 {
                     score *= 200;
@@ -260,11 +345,32 @@ mod test {
         t!("cd", ":cd", "editing.txt");
         t!("'cd", "'cd'", "options.txt");
         t!("\\c", "/\\c", "pattern.txt");
-        t!("let-&", ":let-&", "eval.txt");
+        t!("let-&", ":let-&", "vimeval.txt");
         t!("wildmenu", "'wildmenu'", "options.txt");
         t!("'wildmenu'", "'wildmenu'", "options.txt");
     }
 
+    #[test]
+    fn keycodes_work() {
+        t!("<C-w>v", "CTRL-W_v", "windows.txt");
+        t!("<c-w><c-v>", "CTRL-W_CTRL-V", "windows.txt");
+        t!("<C-W><Down>", "CTRL-W_<Down>", "windows.txt");
+        t!("<Leader>", "<Leader>", "map.txt");
+        t!("i_<C-r>", "i_CTRL-R", "insert.txt");
+        t!("i_<C-x><C-n>", "i_CTRL-X_CTRL-N", "insert.txt");
+        t!("c_<C-r><C-w>", "c_CTRL-R_CTRL-W", "cmdline.txt");
+        t!("t_<C-\\><C-o>", "t_CTRL-\\_CTRL-O", "terminal.txt");
+        t!("v_o", "v_o", "visual.txt");
+        t!("<C-Left>", "<C-Left>", "motion.txt");
+        t!("i_<S-Left>", "i_<S-Left>", "insert.txt");
+        t!("<M-x>", "META", "intro.txt");
+        t!("i_<A-x>", "i_META", "insert.txt");
+
+        assert_eq!(normalize_keycodes("<S-a>"), "A");
+        assert_eq!(normalize_keycodes("[<C-i>"), "[_CTRL-I");
+        assert_eq!(normalize_keycodes("'cd"), "'cd");
+    }
+
     #[test]
     fn encode_works() {
         assert_eq!(encode_url(":terminal"), "%3Aterminal");
@@ -1,59 +1,135 @@
 #![feature(try_blocks)]
 #![allow(clippy::result_large_err)]
 
+mod cli;
 mod command;
 mod config;
 mod gif;
 mod help;
+mod media;
+mod ratelimit;
+mod replies;
+mod scan;
+mod state;
+mod thumbnail;
 
-use std::path::Path;
+use std::cell::{Cell, OnceCell, Ref, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use serde_json::Value as Json;
+use signal_hook::consts::{SIGINT, SIGTERM};
 
-use std::io::Read;
+use std::io::{IsTerminal, Read, Write};
 
-const DEFAULT_HOMESERVER: &str = "https://matrix.org";
+/// How many of our gifs can be rerolled, since we have to keep their search results around
+const MAX_GIF_PICKERS: usize = 100;
 
-#[derive(serde::Deserialize)]
-struct MxcUriCreateResponse {
-    content_uri: String,
-    #[allow(unused)]
-    unused_expires_at: i64,
+/// How often to check whether the room aliases in the config go to different rooms now
+const ALIAS_REFRESH: Duration = Duration::from_secs(60 * 60);
+
+/// How long the bot can be down for and still reply to what was sent in the meantime, since
+/// replies to hours-old messages would be more confusing than helpful
+const MAX_SYNC_POSITION_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// Who we are, whoever's typing commands, and where, in the REPL
+const OFFLINE_USER_ID: &str = "@nvim-bot:localhost";
+const OFFLINE_SENDER: &str = "@you:localhost";
+const OFFLINE_ROOM_ID: &str = "!repl:localhost";
+
+/// The message that triggered whatever we're replying with.
+struct Trigger<'a> {
+    room_id: &'a str,
+    sender: &'a str,
+    /// ID of the original message, even when we're handling an edit of it
+    event_id: &'a str,
+    /// Our replies from before the message was edited, which get edited (in order) to the new
+    /// replies instead of sending new messages
+    old_replies: RefCell<VecDeque<String>>,
+    /// The [`command::Command::key`] of the command being handled, remembered with its replies
+    command: RefCell<String>,
 }
 
 struct MatrixClient {
     access_token: Option<String>,
+    /// Our own user ID, which we get when logging in
+    user_id: Option<String>,
     command_parser: command::CommandParser,
     homeserver: String,
     agent: ureq::Agent,
 
-    /// If this is None, then gifs won't be supported
-    tenor_api_key: Option<String>,
-    /// If this is None, giphy won't be supported
-    giphy_api_key: Option<String>,
+    /// If this is empty, then gifs won't be supported
+    gif_providers: gif::Providers,
 
-    config: config::Config,
+    /// The config file, with the room settings changed with `!config` on top
+    config: RefCell<config::Config>,
+    room_settings: RefCell<state::RoomSettings>,
+    replies: RefCell<replies::Replies>,
+    /// The search results for gifs we sent, by event ID, for rerolling them
+    gif_pickers: RefCell<VecDeque<(String, gif::Picker)>>,
+    media_cache: RefCell<media::MediaCache>,
+    /// The biggest upload the homeserver allows, fetched when we first need it (and again until
+    /// fetching it works)
+    max_upload_size: OnceCell<Option<u64>>,
+    rate_limiter: RefCell<ratelimit::RateLimiter>,
+    /// When we started (in milliseconds since the epoch), which along with the counter makes
+    /// transaction IDs unique
+    started: u128,
+    txn_counter: Cell<u64>,
+    /// Print what we'd send instead of sending it, see [`MatrixClient::new_offline`]
+    offline: bool,
 }
 
 impl MatrixClient {
-    fn new(
-        homeserver: String,
-        tenor_api_key: Option<String>,
-        giphy_api_key: Option<String>,
-        config: config::Config,
-    ) -> Self {
+    fn new(homeserver: String, gif_providers: gif::Providers, mut config: config::Config) -> Self {
+        let room_settings =
+            state::RoomSettings::load(Path::new(&config.state_dir).join("room_settings.json"));
+        room_settings.apply(&mut config);
+        let media_cache = media::MediaCache::load(
+            Path::new(&config.state_dir).join("media_cache.json"),
+            config.media_cache_ttl,
+        );
+
         Self {
             access_token: None,
+            user_id: None,
             // set timeouts?
             agent: ureq::AgentBuilder::new().build(),
             homeserver,
             command_parser: command::CommandParser::new(),
-            tenor_api_key,
-            giphy_api_key,
-            config,
+            gif_providers,
+            config: RefCell::new(config),
+            room_settings: RefCell::new(room_settings),
+            replies: RefCell::new(replies::Replies::default()),
+            gif_pickers: RefCell::new(VecDeque::new()),
+            media_cache: RefCell::new(media_cache),
+            max_upload_size: OnceCell::new(),
+            rate_limiter: RefCell::new(ratelimit::RateLimiter::default()),
+            started: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |time| time.as_millis()),
+            txn_counter: Cell::new(0),
+            offline: false,
         }
     }
 
+    /// A client that prints messages instead of sending them, and pretends to upload media,
+    /// for trying commands out without a homeserver. Whoever uses it can do anything.
+    fn new_offline(gif_providers: gif::Providers, config: config::Config) -> Self {
+        let mut client = Self::new(String::new(), gif_providers, config);
+        client.access_token = Some(String::new());
+        client.user_id = Some(OFFLINE_USER_ID.to_string());
+        client.offline = true;
+        client
+    }
+
+    fn config(&self) -> Ref<'_, config::Config> {
+        self.config.borrow()
+    }
+
     fn login(&mut self, user: &str, password: &str) -> Result<(), ureq::Error> {
         let response: String = self
             .agent
@@ -74,115 +150,332 @@ impl MatrixClient {
 
         let json = serde_json::from_str::<Json>(&response).unwrap();
         self.access_token = Some(json["access_token"].as_str().unwrap().to_string());
+        self.user_id = json["user_id"].as_str().map(String::from);
 
         Ok(())
     }
 
-    fn upload_data_to_matrix<R>(&self, reader: R) -> Result<String, ureq::Error>
-    where
-        R: std::io::Read + Send,
-    {
-        let mxc_uri = self
-            .agent
-            .post(&format!("{}/_matrix/media/v1/create", self.homeserver,))
-            .set("Accept", "application/json")
-            .set("Charset", "utf-8")
-            .query("access_token", self.access_token.as_ref().unwrap())
-            .call()?;
-
-        let mxc_uri = serde_json::from_str::<MxcUriCreateResponse>(&mxc_uri.into_string().unwrap())
-            .unwrap()
-            .content_uri;
-
-        // TODO(smolck): Umm . . . absolutely not lmao
-        let mxc_uri_parts: Vec<&str> = mxc_uri.split("mxc://").collect();
-        let parts = mxc_uri_parts[1].split('/').collect::<Vec<&str>>();
-        let server_name = parts[0];
-        let media_id = parts[1];
+    /// Our login, for saving.
+    fn session(&self) -> Option<state::Session> {
+        Some(state::Session {
+            homeserver: self.homeserver.clone(),
+            user_id: self.user_id.clone()?,
+            access_token: self.access_token.clone()?,
+        })
+    }
 
-        // Upload data to the URI
-        self.agent
-            .put(&format!(
-                "{}/_matrix/media/v3/upload/{}/{}",
-                self.homeserver, server_name, media_id,
-            ))
-            .query("filename", "nvim-bot-gif.gif")
-            .query("access_token", self.access_token.as_ref().unwrap())
-            .set("Content-Type", "application/octet-stream")
-            .send(reader)?;
+    /// Uses a saved login instead of logging in.
+    fn use_session(&mut self, session: state::Session) {
+        self.access_token = Some(session.access_token);
+        self.user_id = Some(session.user_id);
+    }
 
-        Ok(mxc_uri)
+    /// Makes sure our access token still works.
+    fn whoami(&self) -> Result<(), ureq::Error> {
+        self.get_json("account/whoami")?;
+        Ok(())
     }
 
     fn send_gif_if_key_else_do_nothing(
         &self,
         search_query: &str,
-        giphy: bool,
-        room_id: &str,
-    ) -> Result<(), ureq::Error> {
-        // This is kinda jank lmao
-        let Some(gif) = (if giphy {
-            let Some(key) = &self.giphy_api_key else {
-                println!(
-                    "Not searching '{}' with giphy because no api key",
-                    search_query
-                );
-                return Ok(());
-            };
+        provider: Option<&str>,
+        trigger: &Trigger,
+    ) -> Result<(), media::MediaError> {
+        if self
+            .config()
+            .is_blocked_gif_search(trigger.room_id, search_query)
+        {
+            println!("Not searching '{}' because it's blocked", search_query);
+            _ = self.send_message(
+                false,
+                &format!("No gifs found for '{}'", search_query),
+                trigger,
+            );
+            return Ok(());
+        }
 
-            let result = gif::Gif::search_giphy(&self.agent, key, search_query);
-            if result.is_err() {
-                None
-            } else {
-                Some(result.unwrap())
-            }
-        } else {
-            let Some(key) = &self.tenor_api_key else {
-                println!(
-                    "Not searching '{}' with tenor because no api key",
-                    search_query
-                );
+        let wait = self
+            .rate_limiter
+            .borrow_mut()
+            .fetch_gif(self.config().gif_fetches_per_minute);
+        if let Some(wait) = wait {
+            println!(
+                "Not searching '{}', too many gifs have been fetched",
+                search_query
+            );
+            let message = "Too many gifs right now, try again in a minute.";
+            self.throttle_notice("gif", message, wait, trigger);
+            return Ok(());
+        }
 
-                return Ok(());
-            };
-            Some(gif::Gif::search(&self.agent, key, search_query)?)
-        }) else {
+        let gifs = self.gif_providers.search(
+            &self.agent,
+            &self.config().gif_providers(trigger.room_id, provider),
+            search_query,
+            self.config().gif_rating(trigger.room_id),
+        );
+        if gifs.is_empty() {
             _ = self.send_message(
                 false,
                 &format!("No gifs found for '{}'", search_query),
-                room_id,
+                trigger,
             );
             return Ok(());
+        }
+
+        let mut picker = gif::Picker::new(search_query, gifs);
+        let reply_id = self.send_gif(picker.pick().unwrap(), search_query, trigger)?;
+        self.remember_picker(reply_id, picker);
+
+        Ok(())
+    }
+
+    fn remember_picker(&self, gif_id: String, picker: gif::Picker) {
+        let mut pickers = self.gif_pickers.borrow_mut();
+        // We might've edited an older gif into this one
+        pickers.retain(|(id, _)| *id != gif_id);
+        if !picker.can_reroll() {
+            return;
+        }
+
+        if pickers.len() == MAX_GIF_PICKERS {
+            pickers.pop_front();
+        }
+        pickers.push_back((gif_id, picker));
+    }
+
+    /// The biggest gif we'll download and upload, which is the smaller of the configured limit
+    /// and the homeserver's.
+    fn max_media_size(&self) -> u64 {
+        let homeserver_max = match self.max_upload_size.get() {
+            Some(max) => *max,
+            None if self.offline => None,
+            // Only remember it once we've got it, so a failure doesn't stick around
+            None => match media::max_upload_size(
+                &self.agent,
+                &self.homeserver,
+                self.access_token.as_ref().unwrap(),
+            ) {
+                Ok(max) => *self.max_upload_size.get_or_init(|| max),
+                Err(err) => {
+                    eprintln!("Error getting the homeserver's max upload size: {err}");
+                    None
+                }
+            },
         };
+        homeserver_max.map_or(self.config().max_gif_size, |max| {
+            max.min(self.config().max_gif_size)
+        })
+    }
 
-        let gif_bytes_reader = self.agent.get(&gif.url).call()?.into_reader();
+    /// Uploads `gif` (found by searching `query`) and sends it in reply to `trigger`, returning
+    /// the event ID. If the gif is too big (or broken), a smaller version of it is sent instead.
+    fn send_gif(
+        &self,
+        gif: &gif::Gif,
+        query: &str,
+        trigger: &Trigger,
+    ) -> Result<String, media::MediaError> {
+        let mut uploaded = None;
+        let mut last_err = None;
+        for rendition in gif.renditions(self.config().gif_video(trigger.room_id)) {
+            let filename = media::filename(query, &rendition.mimetype);
+            match self.upload_rendition(rendition, &filename, true) {
+                Ok(upload) => {
+                    uploaded = Some((rendition, filename, upload));
+                    break;
+                }
+                Err(
+                    err
+                    @ (media::MediaError::TooLarge { .. } | media::MediaError::WrongType { .. }),
+                ) => {
+                    eprintln!("Not sending {:?}: {}", rendition.source, err);
+                    last_err = Some(err);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        let Some((rendition, filename, uploaded)) = uploaded else {
+            return Err(last_err.unwrap_or(media::MediaError::TooLarge {
+                max: self.max_media_size(),
+            }));
+        };
 
-        let gif_uri = self.upload_data_to_matrix(gif_bytes_reader)?;
+        let mimetype = uploaded.mimetype.as_deref().unwrap_or(&rendition.mimetype);
+        let mut info = serde_json::json!({
+            "mimetype": mimetype,
+            "size": uploaded.size.unwrap_or(rendition.size as u64),
+            "h": uploaded.height.unwrap_or(rendition.height),
+            "w": uploaded.width.unwrap_or(rendition.width),
+        });
+        if let Some(duration) = rendition.duration {
+            info["duration"] = Json::from(duration);
+        }
 
-        let gif_preview_reader = self.agent.get(&gif.preview_url).call()?.into_reader();
-        let gif_preview_uri = self.upload_data_to_matrix(gif_preview_reader)?;
+        // Prefer our own thumbnail, but the gif is still worth sending without one
+        if let Some(thumbnail) = &uploaded.thumbnail {
+            info["thumbnail_url"] = Json::from(thumbnail.mxc_uri.as_str());
+            info["thumbnail_info"] = serde_json::json!({
+                "h": thumbnail.height,
+                "w": thumbnail.width,
+                "mimetype": "image/png",
+                "size": thumbnail.size,
+            });
+            info["xyz.amorgan.blurhash"] = Json::from(thumbnail.blurhash.as_str());
+        } else if let Some(preview) = &gif.preview {
+            let filename = media::filename(&format!("{} preview", query), &preview.mimetype);
+            match self.upload_rendition(preview, &filename, false) {
+                Ok(uploaded) => {
+                    info["thumbnail_url"] = Json::from(uploaded.mxc_uri);
+                    info["thumbnail_info"] = serde_json::json!({
+                        "h": uploaded.height.unwrap_or(preview.height),
+                        "w": uploaded.width.unwrap_or(preview.width),
+                        "mimetype": uploaded.mimetype.as_deref().unwrap_or(&preview.mimetype),
+                        "size": uploaded.size.unwrap_or(preview.size as u64),
+                    });
+                }
+                Err(err) => eprintln!("Not sending preview {:?}: {}", preview.source, err),
+            }
+        }
 
+        let msgtype = if mimetype.starts_with("video/") {
+            "m.video"
+        } else {
+            "m.image"
+        };
         let json = serde_json::json!({
-            "msgtype": "m.image",
-            "info": {
-                "mimetype": "image/gif",
-                "size": gif.size,
-                "h": gif.height,
-                "w": gif.width,
-                "thumbnail_info": {
-                    "h": gif.preview_height,
-                    "w": gif.preview_width,
-                    "mimetype": gif.preview_mimetype,
-                    "size": gif.preview_size,
-                },
-                "thumbnail_url": gif_preview_uri,
+            "msgtype": msgtype,
+            "info": info,
+            "url": uploaded.mxc_uri,
+            "body": gif.attribution,
+            "filename": filename,
+        });
+
+        Ok(self.reply(trigger, json)?)
+    }
+
+    /// Uploads a version of a gif (or its preview) to the homeserver as `filename`, along
+    /// with a thumbnail of it if `thumbnail` is set and it's a gif. Media we've already
+    /// uploaded (going by URL or contents) isn't uploaded again, and media that's too big or
+    /// isn't what the provider says it is isn't uploaded at all.
+    fn upload_rendition(
+        &self,
+        rendition: &gif::Rendition,
+        filename: &str,
+        thumbnail: bool,
+    ) -> Result<media::Uploaded, media::MediaError> {
+        let max_size = self.max_media_size();
+        let (url, bytes) = match &rendition.source {
+            gif::Source::Url(url) => {
+                if let Some(uploaded) = self.media_cache.borrow_mut().get_url(url) {
+                    return Ok(uploaded);
+                }
+                if rendition.size as u64 > max_size {
+                    return Err(media::MediaError::TooLarge { max: max_size });
+                }
+
+                (
+                    Some(url.as_str()),
+                    media::download(&self.agent, url, max_size)?,
+                )
+            }
+            gif::Source::File(path) => (None, media::read_file(path, max_size)?),
+        };
+
+        let hash = media::content_hash(&bytes);
+        let cached = self.media_cache.borrow_mut().get_hash(&hash);
+        let uploaded = match cached {
+            Some(uploaded) => uploaded,
+            None => {
+                let mimetype = media::verify(&bytes, &rendition.mimetype)?;
+                let info = media::sniff(&bytes);
+                media::Uploaded {
+                    mxc_uri: self.upload_bytes(&bytes, mimetype, filename)?,
+                    mimetype: Some(mimetype.to_string()),
+                    size: Some(bytes.len() as u64),
+                    width: info.as_ref().map(|info| info.width),
+                    height: info.as_ref().map(|info| info.height),
+                    thumbnail: if thumbnail && mimetype == "image/gif" {
+                        self.upload_thumbnail(&bytes, filename)
+                    } else {
+                        None
+                    },
+                }
+            }
+        };
+        self.media_cache.borrow_mut().insert(url, &hash, &uploaded);
+
+        Ok(uploaded)
+    }
+
+    /// Makes a thumbnail of a gif and uploads it, logging (and otherwise ignoring) errors.
+    fn upload_thumbnail(&self, gif: &[u8], filename: &str) -> Option<media::UploadedThumbnail> {
+        let result: Result<_, String> = try {
+            let thumbnail = thumbnail::generate(gif).map_err(|err| err.to_string())?;
+            let stem = Path::new(filename)
+                .file_stem()
+                .map_or("gif".into(), |stem| stem.to_string_lossy());
+            let mxc_uri = self
+                .upload_bytes(
+                    &thumbnail.png,
+                    "image/png",
+                    &format!("{}-thumbnail.png", stem),
+                )
+                .map_err(|err| err.to_string())?;
+
+            media::UploadedThumbnail {
+                mxc_uri,
+                width: thumbnail.width,
+                height: thumbnail.height,
+                size: thumbnail.png.len() as u64,
+                blurhash: thumbnail.blurhash,
+            }
+        };
+
+        result
+            .map_err(|err| eprintln!("Error making thumbnail for {}: {}", filename, err))
+            .ok()
+    }
+
+    fn upload_bytes(
+        &self,
+        bytes: &[u8],
+        content_type: &str,
+        filename: &str,
+    ) -> Result<String, media::MediaError> {
+        if self.offline {
+            println!(
+                "(uploading {}, {} bytes of {})",
+                filename,
+                bytes.len(),
+                content_type
+            );
+            return Ok(format!("mxc://localhost/{}", self.txn_id()));
+        }
+
+        Ok(media::upload(
+            &self.agent,
+            &self.homeserver,
+            self.access_token.as_ref().unwrap(),
+            self.config().media_upload,
+            &media::Upload {
+                bytes,
+                content_type,
+                filename,
             },
-            "url": gif_uri,
-            "body": "nvim-bot-gif.gif",
-        })
-        .to_string();
+        )?
+        .to_string())
+    }
+
+    /// Sends a message event to a room, returning its event ID.
+    fn send_event(&self, room_id: &str, content: &Json) -> Result<String, ureq::Error> {
+        if self.offline {
+            print_event(content);
+            return Ok(format!("${}", self.txn_id()));
+        }
 
-        let _response = self
+        let response: String = self
             .agent
             .post(&format!(
                 "{}/_matrix/client/r0/rooms/{}/send/m.room.message",
@@ -192,9 +485,115 @@ impl MatrixClient {
             .set("Content-Type", "application/json")
             .set("Charset", "utf-8")
             .query("access_token", self.access_token.as_ref().unwrap())
-            .send_string(&json)?
+            .send_string(&content.to_string())?
+            .into_string()?;
+
+        let json = serde_json::from_str::<Json>(&response).unwrap_or_default();
+        Ok(json["event_id"].as_str().unwrap_or_default().to_string())
+    }
+
+    /// Sends `content` in reply to `trigger`, or if the triggering message was edited, edits
+    /// one of the replies we sent before instead. Returns the ID of the reply.
+    fn reply(&self, trigger: &Trigger, content: Json) -> Result<String, ureq::Error> {
+        let old_reply = trigger.old_replies.borrow_mut().pop_front();
+        let reply_id = if let Some(old_reply) = old_reply {
+            self.edit(trigger.room_id, &old_reply, content)?;
+            old_reply
+        } else {
+            self.send_event(trigger.room_id, &content)?
+        };
+
+        if !reply_id.is_empty() {
+            self.replies.borrow_mut().push(
+                trigger.event_id,
+                trigger.sender,
+                &trigger.command.borrow(),
+                reply_id.clone(),
+            );
+        }
+        Ok(reply_id)
+    }
+
+    /// Edits one of our messages to have `content` instead.
+    fn edit(&self, room_id: &str, event_id: &str, content: Json) -> Result<(), ureq::Error> {
+        let mut edit = content.clone();
+        for key in ["body", "formatted_body"] {
+            if let Some(text) = content.get(key).and_then(|text| text.as_str()) {
+                edit[key] = Json::from(format!("* {}", text));
+            }
+        }
+        edit["m.new_content"] = content;
+        edit["m.relates_to"] = serde_json::json!({
+            "rel_type": "m.replace",
+            "event_id": event_id,
+        });
+
+        self.send_event(room_id, &edit)?;
+        Ok(())
+    }
+
+    fn get_json(&self, path: &str) -> Result<Json, ureq::Error> {
+        let response = self
+            .agent
+            .get(&format!("{}/_matrix/client/r0/{}", self.homeserver, path))
+            .set("Accept", "application/json")
+            .set("Charset", "utf-8")
+            .query("access_token", self.access_token.as_ref().unwrap())
+            .call()?
             .into_string()?;
 
+        Ok(serde_json::from_str(&response).unwrap_or_default())
+    }
+
+    /// The power level `user_id` has in the room, along with the room's power levels.
+    fn power_level(&self, room_id: &str, user_id: &str) -> Result<(i64, Json), ureq::Error> {
+        if self.offline {
+            return Ok((100, Json::Null));
+        }
+
+        let levels = self.get_json(&format!("rooms/{}/state/m.room.power_levels", room_id))?;
+        let user_level = levels["users"][user_id]
+            .as_i64()
+            .or_else(|| levels["users_default"].as_i64())
+            .unwrap_or(0);
+
+        Ok((user_level, levels))
+    }
+
+    /// Whether `user_id` has enough power in the room to redact other people's messages.
+    fn can_redact_others(&self, room_id: &str, user_id: &str) -> Result<bool, ureq::Error> {
+        let (user_level, levels) = self.power_level(room_id, user_id)?;
+        Ok(user_level >= levels["redact"].as_i64().unwrap_or(50))
+    }
+
+    /// Whether `user_id` has enough power in the room to change its settings.
+    fn can_configure(&self, room_id: &str, user_id: &str) -> Result<bool, ureq::Error> {
+        let (user_level, _) = self.power_level(room_id, user_id)?;
+        Ok(user_level >= self.config().config_power_level)
+    }
+
+    /// A transaction ID that's unique for this run of the bot.
+    fn txn_id(&self) -> String {
+        let count = self.txn_counter.get();
+        self.txn_counter.set(count + 1);
+        format!("nvim-bot-{}-{}", self.started, count)
+    }
+
+    fn redact(&self, room_id: &str, event_id: &str, reason: &str) -> Result<(), ureq::Error> {
+        self.agent
+            .put(&format!(
+                "{}/_matrix/client/r0/rooms/{}/redact/{}/{}",
+                self.homeserver,
+                room_id,
+                event_id,
+                self.txn_id()
+            ))
+            .set("Accept", "application/json")
+            .set("Content-Type", "application/json")
+            .set("Charset", "utf-8")
+            .query("access_token", self.access_token.as_ref().unwrap())
+            .send_string(&serde_json::json!({ "reason": reason }).to_string())?;
+
         Ok(())
     }
 
@@ -202,7 +601,7 @@ impl MatrixClient {
         &self,
         use_markdown: bool,
         message: &str,
-        room_id: &str,
+        trigger: &Trigger,
     ) -> Result<(), ureq::Error> {
         let json = if use_markdown {
             let mut html_message = String::new();
@@ -217,29 +616,14 @@ impl MatrixClient {
                 "format": "org.matrix.custom.html",
                 "formatted_body": html_message,
             })
-            .to_string()
         } else {
             serde_json::json!({
                 "msgtype": "m.text",
                 "body": message,
             })
-            .to_string()
         };
 
-        // TODO(smolck): Maybe deal with response or use it or something?
-        let _response: String = self
-            .agent
-            .post(&format!(
-                "{}/_matrix/client/r0/rooms/{}/send/m.room.message",
-                self.homeserver, room_id
-            ))
-            .set("Accept", "application/json")
-            .set("Content-Type", "application/json")
-            .set("Charset", "utf-8")
-            .query("access_token", self.access_token.as_ref().unwrap())
-            .send_string(&json)?
-            .into_string()?;
-
+        self.reply(trigger, json)?;
         Ok(())
     }
 
@@ -272,19 +656,83 @@ impl MatrixClient {
         let mut buf: Vec<u8> = vec![];
         req.call()?
             .into_reader()
-            .take((10 * 1_024 * 1_024 * 10) + 1 as u64)
+            .take((10 * 1_024 * 1_024 * 10) + 1_u64)
             .read_to_end(&mut buf)
             .expect("sync_once: Failure reading response into buffer");
         let response = String::from_utf8_lossy(&buf);
         let response_json = serde_json::from_str::<Json>(&response).unwrap();
 
-        self.handle_sync_response(&response_json);
+        self.handle_sync_response(&response_json, next_batch.is_some());
 
         Ok(response_json["next_batch"].as_str().unwrap().to_string())
     }
 
-    fn handle_cmd(&self, cmd: command::Command, room_id: &str) {
+    /// Whether `cmd` is being used too often, telling whoever used it to slow down if so (and
+    /// the room wants that).
+    fn throttled(&self, cmd: &command::Command, trigger: &Trigger) -> bool {
+        // Commands that are turned off get ignored anyway
+        if !self.config().command_enabled(trigger.room_id, cmd.name()) {
+            return false;
+        }
+        // Edits that just replace a reply we already sent don't count as using the command
+        // again
+        if !trigger.old_replies.borrow().is_empty() {
+            return false;
+        }
+
+        let Some(wait) = self.rate_limiter.borrow_mut().wait(
+            trigger.room_id,
+            trigger.sender,
+            cmd.name(),
+            self.config().cooldown(trigger.room_id, cmd.name()),
+        ) else {
+            return false;
+        };
+
+        println!("Throttling {} from {}", cmd.name(), trigger.sender);
+        let message = format!(
+            "Slow down! You can use {} again in {} seconds.",
+            cmd.name(),
+            wait.as_secs() + 1
+        );
+        self.throttle_notice(cmd.name(), &message, wait, trigger);
+        true
+    }
+
+    /// Tells whoever sent `trigger` that they're being throttled, if the room wants that and
+    /// they haven't been told in the last `quiet_for`. Replies to edits are never replaced with
+    /// this.
+    fn throttle_notice(
+        &self,
+        command: &'static str,
+        message: &str,
+        quiet_for: Duration,
+        trigger: &Trigger,
+    ) {
+        if !self.config().throttle_replies(trigger.room_id)
+            || !trigger.old_replies.borrow().is_empty()
+            || !self.rate_limiter.borrow_mut().notify(
+                trigger.room_id,
+                trigger.sender,
+                command,
+                quiet_for,
+            )
+        {
+            return;
+        }
+
+        self.send_message(false, message, trigger)
+            .unwrap_or_else(|err| {
+                eprintln!("Error sending throttling message: {err}");
+            });
+    }
+
+    fn handle_cmd(&self, cmd: command::Command, trigger: &Trigger) {
         use command::Command::*;
+        if self.throttled(&cmd, trigger) {
+            return;
+        }
+
         match cmd {
             Help { ref docs } => {
                 let mut tags = vec![];
@@ -311,7 +759,7 @@ impl MatrixClient {
                     .join("\n");
 
                 if !body.is_empty() {
-                    self.send_message(true, &body, room_id)
+                    self.send_message(true, &body, trigger)
                         .unwrap_or_else(|err| {
                             eprintln!("Error sending message for {cmd:?} cmd: {err}");
                         });
@@ -326,52 +774,607 @@ impl MatrixClient {
                             .collect::<Vec<String>>()
                             .join("\n")
                     );
-                    self.send_message(true, &not_found_body, room_id)
+                    self.send_message(true, &not_found_body, trigger)
                         .unwrap_or_else(|err| {
                             eprintln!("Error sending message for {cmd:?} cmd: {err}");
                         });
                 }
             }
             Sandwich { to } => {
-                if let Some(Err(err)) = self.config.rooms.get(room_id).and_then(|config| {
-                    if config.sandwich {
-                        Some(self.send_message(
-                            true,
-                            &format!("here's a sandwich, {}: 🥪", to),
-                            room_id,
-                        ))
-                    } else {
-                        None
-                    }
-                }) {
-                    eprintln!("Error sending sandwich! {}", err);
+                if self.config().room(trigger.room_id).sandwich {
+                    self.send_message(true, &format!("here's a sandwich, {}: 🥪", to), trigger)
+                        .unwrap_or_else(|err| {
+                            eprintln!("Error sending sandwich! {}", err);
+                        });
                 }
             }
             Url { url } => {
-                self.send_message(true, url, room_id).unwrap_or_else(|err| {
+                self.send_message(true, url, trigger).unwrap_or_else(|err| {
                     eprintln!("Error sending URL {url}: {err}");
                 });
             }
-            Gif { search, giphy } => {
-                if let Some(Err(err)) = self.config.rooms.get(room_id).and_then(|config| {
-                    if config.gifs {
-                        Some(self.send_gif_if_key_else_do_nothing(&search, giphy, room_id))
-                    } else {
-                        None
+            Gif { search, provider } => {
+                if self.config().room(trigger.room_id).gifs {
+                    self.send_gif_if_key_else_do_nothing(&search, provider, trigger)
+                        .unwrap_or_else(|err| {
+                            eprintln!("Error sending gif! {}", err);
+                        });
+                }
+            }
+            Config { setting, value } => {
+                self.configure(setting, value, trigger)
+                    .unwrap_or_else(|err| {
+                        eprintln!("Error handling {cmd:?} cmd: {err}");
+                    });
+            }
+        }
+    }
+
+    /// Shows the room's settings (or just `setting`), or changes `setting` to `value`.
+    fn configure(
+        &self,
+        setting: Option<&str>,
+        value: Option<&str>,
+        trigger: &Trigger,
+    ) -> Result<(), ureq::Error> {
+        if !self.can_configure(trigger.room_id, trigger.sender)? {
+            return self.send_message(
+                false,
+                "Only moderators can change my settings here.",
+                trigger,
+            );
+        }
+
+        let room = self.config().room(trigger.room_id).clone();
+        let Some((setting, value)) = setting.zip(value) else {
+            let json = serde_json::to_value(&room).unwrap_or_default();
+            let settings = config::ROOM_SETTINGS
+                .iter()
+                .filter(|key| setting.is_none_or(|setting| setting == **key))
+                .map(|key| {
+                    let value = match &json[key] {
+                        Json::Null => "default".to_string(),
+                        Json::Bool(true) => "on".to_string(),
+                        Json::Bool(false) => "off".to_string(),
+                        Json::String(value) => value.clone(),
+                        value => value.to_string(),
+                    };
+                    format!("* `{}`: `{}`", key, value)
+                })
+                .collect::<Vec<String>>();
+
+            return if settings.is_empty() {
+                self.send_message(
+                    true,
+                    &format!(
+                        "There's no setting called `{}`",
+                        setting.unwrap_or_default()
+                    ),
+                    trigger,
+                )
+            } else {
+                self.send_message(true, &settings.join("\n"), trigger)
+            };
+        };
+
+        let mut settings = self
+            .room_settings
+            .borrow()
+            .get(trigger.room_id)
+            .cloned()
+            .unwrap_or_default();
+        let result: Result<(), String> = try {
+            settings.insert(setting.to_string(), config::setting_value(setting, value)?);
+            // Make sure the setting works before saving it anywhere
+            self.config()
+                .room_with_settings(trigger.room_id, &settings)
+                .map_err(|err| err.to_string())?;
+            self.put_room_settings(trigger.room_id, &settings)
+                .map_err(|err| format!("couldn't save it ({})", err))?;
+            self.use_room_settings(trigger.room_id, settings)
+                .map_err(|err| err.to_string())?;
+        };
+
+        let message = match result {
+            Ok(()) => {
+                println!(
+                    "{} set {} to {} in {}",
+                    trigger.sender, setting, value, trigger.room_id
+                );
+                format!("Set `{}` to `{}`", setting, value)
+            }
+            Err(err) => format!("Couldn't set `{}`: {}", setting, err),
+        };
+        self.send_message(true, &message, trigger)
+    }
+
+    /// Starts using new settings for a room, replacing its old ones.
+    fn use_room_settings(
+        &self,
+        room_id: &str,
+        settings: serde_json::Map<String, Json>,
+    ) -> Result<(), config::SettingError> {
+        self.config
+            .borrow_mut()
+            .set_room_settings(room_id, &settings)?;
+        self.room_settings.borrow_mut().set(room_id, settings);
+        Ok(())
+    }
+
+    fn room_settings_path(&self, room_id: &str) -> String {
+        format!(
+            "user/{}/rooms/{}/account_data/{}",
+            self.user_id.as_deref().unwrap_or_default(),
+            room_id,
+            state::ACCOUNT_DATA_TYPE
+        )
+    }
+
+    /// Saves a room's settings to its account data.
+    fn put_room_settings(
+        &self,
+        room_id: &str,
+        settings: &serde_json::Map<String, Json>,
+    ) -> Result<(), ureq::Error> {
+        if self.offline {
+            return Ok(());
+        }
+
+        self.agent
+            .put(&format!(
+                "{}/_matrix/client/r0/{}",
+                self.homeserver,
+                self.room_settings_path(room_id)
+            ))
+            .set("Accept", "application/json")
+            .set("Content-Type", "application/json")
+            .set("Charset", "utf-8")
+            .query("access_token", self.access_token.as_ref().unwrap())
+            .send_string(&Json::Object(settings.clone()).to_string())?;
+
+        Ok(())
+    }
+
+    /// Saves room settings that we only have locally (from before they were kept in account
+    /// data) to account data. Settings that are already there come in when we sync.
+    fn upload_local_room_settings(&self) {
+        let rooms: Vec<String> = self.room_settings.borrow().rooms().cloned().collect();
+        for room_id in rooms {
+            let result = match self.get_json(&self.room_settings_path(&room_id)) {
+                Err(ureq::Error::Status(404, _)) => {
+                    let settings = self.room_settings.borrow().get(&room_id).cloned();
+                    self.put_room_settings(&room_id, &settings.unwrap_or_default())
+                }
+                result => result.map(|_| ()),
+            };
+            if let Err(err) = result {
+                eprintln!("Error uploading settings for {room_id}: {err}");
+            }
+        }
+    }
+
+    /// Resolves the room aliases in the config to room IDs, so their settings apply. Aliases
+    /// that don't resolve keep whatever they resolved to before, if anything.
+    fn resolve_room_aliases(&self) {
+        let mut aliases = HashMap::new();
+        let old_aliases: Vec<(String, Option<String>)> = self
+            .config()
+            .room_aliases()
+            .map(|alias| {
+                let room_id = self.config().alias_room_id(alias).map(String::from);
+                (alias.to_string(), room_id)
+            })
+            .collect();
+        let mut changed = false;
+        for (alias, old_room_id) in old_aliases {
+            // Aliases start with `#`, which would end the path
+            let path = format!("directory/room/{}", alias.replace('#', "%23"));
+            let room_id = match self.get_json(&path) {
+                Ok(response) => response["room_id"].as_str().map(String::from),
+                Err(err) => {
+                    eprintln!(
+                        "Warning: couldn't resolve {} from the config: {}",
+                        alias, err
+                    );
+                    None
+                }
+            };
+            let room_id = match (room_id, old_room_id.clone()) {
+                (Some(room_id), _) => room_id,
+                (None, Some(old_room_id)) => old_room_id,
+                (None, None) => {
+                    eprintln!(
+                        "Warning: ignoring the settings for {} until it resolves",
+                        alias
+                    );
+                    continue;
+                }
+            };
+            changed |= old_room_id.as_ref() != Some(&room_id);
+            aliases.insert(alias, room_id);
+        }
+        if !changed {
+            return;
+        }
+
+        let mut config = self.config.borrow_mut();
+        config.set_aliases(aliases);
+        self.room_settings.borrow().apply(&mut config);
+    }
+
+    /// Handles the room settings in a room's account data changing (or showing up when we
+    /// first sync).
+    fn handle_room_settings(&self, room_id: &str, event: &Json) -> Option<()> {
+        let settings = event.get("content")?.as_object()?.clone();
+        if self.room_settings.borrow().get(room_id) == Some(&settings) {
+            return Some(());
+        }
+
+        self.use_room_settings(room_id, settings)
+            .unwrap_or_else(|err| {
+                eprintln!("Ignoring broken settings for {room_id}: {err}");
+            });
+        Some(())
+    }
+
+    /// If a message starts by mentioning us (`nvim-bot: help foo`), returns the rest of its
+    /// plain body after the mention.
+    fn strip_mention<'a>(&self, content: &'a serde_json::Map<String, Json>) -> Option<&'a str> {
+        let user_id = self.user_id.as_deref()?;
+        let mut body = content.get("body")?.as_str()?;
+
+        // Skip the fallback of the message being replied to, if any
+        if body.starts_with("> ") {
+            body = body.split_once("\n\n").map_or("", |(_, rest)| rest);
+        }
+
+        let localpart = user_id
+            .trim_start_matches('@')
+            .split(':')
+            .next()
+            .unwrap_or_default();
+        // Replying to one of our messages mentions us too, which doesn't mean the reply is
+        // talking to us
+        let reply = content
+            .get("m.relates_to")
+            .is_some_and(|relates_to| relates_to.get("m.in_reply_to").is_some());
+        let mentioned = !reply
+            && content
+                .get("m.mentions")
+                .and_then(|mentions| mentions.get("user_ids"))
+                .and_then(|ids| ids.as_array())
+                .is_some_and(|ids| ids.iter().any(|id| id.as_str() == Some(user_id)));
+
+        let rest = if let Some(rest) = body.strip_prefix(user_id) {
+            rest
+        } else if body
+            .get(..localpart.len())
+            .is_some_and(|start| !localpart.is_empty() && start.eq_ignore_ascii_case(localpart))
+        {
+            &body[localpart.len()..]
+        } else if mentioned {
+            // Clients put the display name in the body, which could be just about anything
+            let (name, rest) = body.split_once(':')?;
+            if name.contains('\n') {
+                return None;
+            }
+            rest
+        } else {
+            return None;
+        };
+
+        // The mention has to be followed by a separator, so `nvim-botfoo` doesn't count
+        if !rest.starts_with([':', ',', ' ']) {
+            return None;
+        }
+        Some(rest.trim_start_matches([':', ',']).trim_start())
+    }
+
+    fn handle_message(&self, room_id: &str, event: &Json) -> Option<()> {
+        let sender = event.get("sender")?.as_str()?;
+        let event_id = event.get("event_id")?.as_str()?;
+        let mut content = event.get("content")?.as_object()?;
+
+        // Don't reply to ourselves
+        if Some(sender) == self.user_id.as_deref() {
+            return None;
+        }
+
+        // If this is an edit, the new message is in `m.new_content` (the rest being a
+        // fallback for clients without edit support) and we reply as if it's the original
+        let mut original_id = event_id;
+        if let Some(relates_to) = content
+            .get("m.relates_to")
+            .filter(|relates_to| relates_to["rel_type"] == "m.replace")
+        {
+            original_id = relates_to.get("event_id")?.as_str()?;
+            content = content.get("m.new_content")?.as_object()?;
+
+            // Clients ignore edits from anyone but the original sender, so we do too
+            let original_sender = self.replies.borrow().sender(original_id).map(String::from);
+            let original_sender = match original_sender {
+                Some(original_sender) => original_sender,
+                None => self
+                    .get_json(&format!("rooms/{}/event/{}", room_id, original_id))
+                    .map_err(|err| eprintln!("Error getting edited event {original_id}: {err}"))
+                    .ok()?
+                    .get("sender")?
+                    .as_str()?
+                    .to_string(),
+            };
+            if original_sender != sender {
+                println!("Ignoring {sender}'s edit of {original_sender}'s message {original_id}");
+                return None;
+            }
+        }
+
+        // Use formatted_body if available
+        let (mut body, escape_reply) = if let Some(body) = content.get("formatted_body") {
+            (body.as_str()?, true)
+        } else {
+            (content.get("body")?.as_str()?, false)
+        };
+
+        // Don't search in the message the user is replying to so we don't
+        // duplicate messages if the message being replied to had a
+        // help doc reference
+        if escape_reply {
+            // TODO(smolck): This feels like it could be broken pretty
+            // easily. But hopefully not? Since stuff like this *should* get
+            // escaped if it was typed by the user . . . I think. Maybe.
+            body = body
+                .split("</mx_reply>")
+                .collect::<Vec<_>>()
+                .get(1)
+                .unwrap_or(&body);
+        }
+
+        let prefix = self.config().prefix(room_id).to_string();
+        let mut cmds = vec![];
+        if let Some(text) = self.strip_mention(content) {
+            cmds.extend(self.command_parser.parse_mention(text, &prefix));
+        }
+        for cmd in self.command_parser.parse(body, escape_reply, &prefix) {
+            if !cmds.contains(&cmd) {
+                cmds.push(cmd);
+            }
+        }
+
+        // Commands the edit didn't change keep the replies they already have, and the rest of
+        // the old replies get edited to whatever the new commands say
+        let keys: Vec<String> = cmds.iter().map(command::Command::key).collect();
+        let mut unchanged = vec![];
+        let mut old_replies = VecDeque::new();
+        for (reply, command) in self.replies.borrow().get(original_id) {
+            if keys.contains(command) {
+                unchanged.push(command.clone());
+            } else {
+                old_replies.push_back(reply.clone());
+            }
+        }
+        cmds.retain(|cmd| !unchanged.contains(&cmd.key()));
+
+        let trigger = Trigger {
+            room_id,
+            sender,
+            event_id: original_id,
+            old_replies: RefCell::new(old_replies),
+            command: RefCell::default(),
+        };
+        for cmd in cmds {
+            *trigger.command.borrow_mut() = cmd.key();
+            self.handle_cmd(cmd, &trigger);
+        }
+
+        // The edited message got fewer replies than before, so the rest answer something that
+        // isn't there anymore
+        for reply in trigger.old_replies.take() {
+            self.replies.borrow_mut().remove_reply(&reply);
+            let result = if self.config().redact_replies(room_id) {
+                self.redact(room_id, &reply, "Message being replied to was edited")
+            } else {
+                let notice = serde_json::json!({
+                    "msgtype": "m.notice",
+                    "body": "(removed, the message this replied to was edited)",
+                });
+                self.edit(room_id, &reply, notice)
+            };
+            result.unwrap_or_else(|err| eprintln!("Error removing reply {reply}: {err}"));
+        }
+
+        Some(())
+    }
+
+    /// Runs the commands in each line from stdin as if they were sent in `room_id`, printing
+    /// our replies. See [`MatrixClient::new_offline`].
+    fn repl(&self, room_id: &str) -> std::io::Result<()> {
+        let interactive = std::io::stdin().is_terminal();
+        let prompt = || -> std::io::Result<()> {
+            if interactive {
+                print!("> ");
+                std::io::stdout().flush()?;
+            }
+            Ok(())
+        };
+
+        prompt()?;
+        for line in std::io::stdin().lines() {
+            let line = line?;
+            let event_id = format!("${}", self.txn_id());
+            let prefix = self.config().prefix(room_id).to_string();
+            let cmds = self.command_parser.parse(&line, false, &prefix);
+            if cmds.is_empty() && !line.trim().is_empty() {
+                println!("(no commands)");
+            }
+
+            let trigger = Trigger {
+                room_id,
+                sender: OFFLINE_SENDER,
+                event_id: &event_id,
+                old_replies: RefCell::new(VecDeque::new()),
+                command: RefCell::default(),
+            };
+            for cmd in cmds {
+                *trigger.command.borrow_mut() = cmd.key();
+                self.handle_cmd(cmd, &trigger);
+            }
+            prompt()?;
+        }
+
+        Ok(())
+    }
+
+    fn handle_redaction(&self, room_id: &str, event: &Json) -> Option<()> {
+        // Newer room versions moved `redacts` into the content
+        let redacts = event
+            .get("redacts")
+            .or_else(|| event.get("content")?.get("redacts"))?
+            .as_str()?;
+
+        let replies = self.replies.borrow_mut().remove(redacts);
+        if !self.config().redact_replies(room_id) {
+            return Some(());
+        }
+
+        for reply in replies {
+            self.redact(room_id, &reply, "Message being replied to was redacted")
+                .unwrap_or_else(|err| {
+                    eprintln!("Error redacting reply {reply}: {err}");
+                });
+        }
+
+        Some(())
+    }
+
+    /// Replaces one of our gifs with the next one from its search, if `sender` is the one who
+    /// asked for it.
+    fn reroll_gif(&self, room_id: &str, gif_id: &str, sender: &str) -> Option<()> {
+        let replies = self.replies.borrow();
+        if replies.requester(gif_id)? != sender {
+            return None;
+        }
+        let event_id = replies.replied_to(gif_id)?.to_string();
+        let command = replies.command(gif_id)?.to_string();
+        drop(replies);
+
+        // Only gifs we still have the search results for can be rerolled, and only those count
+        // towards the gif limit
+        let mut pickers = self.gif_pickers.borrow_mut();
+        let idx = pickers.iter().position(|(id, _)| id == gif_id)?;
+        if self
+            .rate_limiter
+            .borrow_mut()
+            .fetch_gif(self.config().gif_fetches_per_minute)
+            .is_some()
+        {
+            println!("Not rerolling {gif_id}, too many gifs have been fetched");
+            return None;
+        }
+        let (_, mut picker) = pickers.remove(idx)?;
+        drop(pickers);
+
+        let trigger = Trigger {
+            room_id,
+            sender,
+            event_id: &event_id,
+            old_replies: RefCell::new(VecDeque::new()),
+            command: RefCell::new(command),
+        };
+        let query = picker.query().to_string();
+        let new_id = match self.send_gif(picker.pick()?, &query, &trigger) {
+            Ok(new_id) => new_id,
+            Err(err) => {
+                eprintln!("Error rerolling gif {gif_id}: {err}");
+                self.remember_picker(gif_id.to_string(), picker);
+                return None;
+            }
+        };
+
+        self.redact(room_id, gif_id, "Rerolled")
+            .unwrap_or_else(|err| {
+                eprintln!("Error redacting rerolled gif {gif_id}: {err}");
+            });
+        self.replies.borrow_mut().remove_reply(gif_id);
+        self.remember_picker(new_id, picker);
+
+        Some(())
+    }
+
+    fn handle_reaction(&self, room_id: &str, event: &Json) -> Option<()> {
+        let sender = event.get("sender")?.as_str()?;
+        let relates_to = event.get("content")?.get("m.relates_to")?;
+        let reacted_to = relates_to.get("event_id")?.as_str()?;
+        let key = relates_to.get("key")?.as_str()?;
+
+        if relates_to.get("rel_type")? != "m.annotation" || Some(sender) == self.user_id.as_deref()
+        {
+            return None;
+        }
+
+        if self.config().is_reroll_reaction(key) {
+            return self.reroll_gif(room_id, reacted_to, sender);
+        } else if !self.config().is_delete_reaction(key) {
+            return None;
+        }
+
+        let requester = self
+            .replies
+            .borrow()
+            .requester(reacted_to)
+            .map(String::from);
+        let allowed = match requester {
+            Some(requester) if requester == sender => true,
+            _ => {
+                // If we don't remember the message, make sure it's actually one of ours
+                if requester.is_none() {
+                    let reacted_event = self
+                        .get_json(&format!("rooms/{}/event/{}", room_id, reacted_to))
+                        .ok()?;
+                    if reacted_event["sender"].as_str() != self.user_id.as_deref() {
+                        return None;
                     }
-                }) {
-                    eprintln!("Error sending gif! {}", err);
                 }
+
+                self.can_redact_others(room_id, sender)
+                    .unwrap_or_else(|err| {
+                        eprintln!("Error getting power levels for {room_id}: {err}");
+                        false
+                    })
             }
+        };
+
+        if allowed {
+            self.redact(room_id, reacted_to, &format!("Deleted by {}", sender))
+                .unwrap_or_else(|err| {
+                    eprintln!("Error redacting {reacted_to}: {err}");
+                });
+            self.replies.borrow_mut().remove_reply(reacted_to);
         }
+
+        Some(())
     }
 
-    fn handle_sync_response(&self, response: &Json) {
+    /// Handles a sync, skipping its timeline unless `timeline` is set, since a sync that
+    /// doesn't carry on from an earlier one starts with old history that's either been handled
+    /// already or is too old to answer.
+    fn handle_sync_response(&self, response: &Json, timeline: bool) {
         if let Some(joined) = response["rooms"]
             .as_object()
             .and_then(|rooms| rooms["join"].as_object())
         {
-            for (_room, room_id) in joined.values().zip(joined.keys()) {
+            for (room, room_id) in joined.values().zip(joined.keys()) {
+                // Before the timeline, so new settings apply to messages in the same sync
+                if let Some(events) = room["account_data"]["events"].as_array() {
+                    for event in events {
+                        if event["type"] == state::ACCOUNT_DATA_TYPE {
+                            self.handle_room_settings(room_id, event);
+                        }
+                    }
+                }
+
+                if !timeline {
+                    continue;
+                }
                 if let Some(events) = joined
                     .get(room_id)
                     .and_then(|room| {
@@ -382,36 +1385,11 @@ impl MatrixClient {
                 {
                     for event in events {
                         let _: Option<_> = try {
-                            let event_type = event.get("type")?.as_str().unwrap();
-                            let _sender = event.get("sender")?.as_str().unwrap();
-                            let content = event.get("content")?.as_object().unwrap();
-
-                            // Use formatted_body if available
-                            let (mut body, escape_reply) =
-                                if let Some(body) = content.get("formatted_body") {
-                                    (body.as_str().unwrap(), true)
-                                } else {
-                                    (content.get("body")?.as_str().unwrap(), false)
-                                };
-
-                            // Don't search in the message the user is replying to so we don't
-                            // duplicate messages if the message being replied to had a
-                            // help doc reference
-                            if escape_reply {
-                                // TODO(smolck): This feels like it could be broken pretty
-                                // easily. But hopefully not? Since stuff like this *should* get
-                                // escaped if it was typed by the user . . . I think. Maybe.
-                                body = body
-                                    .split("</mx_reply>")
-                                    .collect::<Vec<_>>()
-                                    .get(1)
-                                    .unwrap_or(&body);
-                            }
-
-                            if event_type == "m.room.message" {
-                                if let Some(cmd) = self.command_parser.parse(body) {
-                                    self.handle_cmd(cmd, room_id);
-                                }
+                            match event.get("type")?.as_str()? {
+                                "m.room.message" => self.handle_message(room_id, event)?,
+                                "m.room.redaction" => self.handle_redaction(room_id, event)?,
+                                "m.reaction" => self.handle_reaction(room_id, event)?,
+                                _ => {}
                             }
                         };
                     }
@@ -420,71 +1398,329 @@ impl MatrixClient {
         }
     }
 
-    fn sync(&self) -> Result<(), ureq::Error> {
-        let mut next_batch: Option<String> = None;
-        loop {
+    /// Syncs (and handles whatever comes in) until `stop` is set, saving where we got up to
+    /// after each sync so the next run carries on from there.
+    fn sync(&self, stop: &AtomicBool) -> Result<(), ureq::Error> {
+        let state_dir = self.config().state_dir.clone();
+        let user_id = self.user_id.clone().unwrap_or_default();
+        let mut next_batch = match state::SyncPosition::load(&state_dir, &user_id) {
+            Some(position) if position.age() > MAX_SYNC_POSITION_AGE => {
+                // The first sync then just finds out where things are now
+                println!(
+                    "Not catching up from {} minutes ago, that's too long",
+                    position.age().as_secs() / 60
+                );
+                None
+            }
+            position => position.map(|position| position.next_batch),
+        };
+        let mut aliases_resolved: Option<Instant> = None;
+        while !stop.load(Ordering::Relaxed) {
+            if aliases_resolved.is_none_or(|resolved| resolved.elapsed() >= ALIAS_REFRESH) {
+                self.resolve_room_aliases();
+                aliases_resolved = Some(Instant::now());
+            }
             match self.sync_once(next_batch.as_deref(), None) {
                 Ok(new_batch) => {
+                    // Everything in that sync has been handled (and replied to) by now, so a
+                    // crash or restart picks up right after it
+                    let position = state::SyncPosition::new(user_id.clone(), new_batch.clone());
+                    if let Err(err) = position.save(&state_dir) {
+                        eprintln!("Error saving the sync position: {}", err);
+                    }
                     next_batch = Some(new_batch);
                 }
                 // TODO(smolck): I don't think we need to crash on this
                 Err(err) => eprintln!("Error syncing! {}", err),
             }
-            std::thread::sleep(std::time::Duration::from_millis(1000));
+            if !stop.load(Ordering::Relaxed) {
+                std::thread::sleep(std::time::Duration::from_millis(1000));
+            }
         }
+
+        println!("Shutting down");
+        if self.config().offline_on_exit {
+            self.set_presence("offline")
+                .unwrap_or_else(|err| eprintln!("Error setting presence: {}", err));
+        }
+
+        Ok(())
+    }
+
+    fn set_presence(&self, presence: &str) -> Result<(), ureq::Error> {
+        self.agent
+            .put(&format!(
+                "{}/_matrix/client/r0/presence/{}/status",
+                self.homeserver,
+                self.user_id.as_deref().unwrap_or_default()
+            ))
+            .set("Accept", "application/json")
+            .set("Content-Type", "application/json")
+            .set("Charset", "utf-8")
+            .query("access_token", self.access_token.as_ref().unwrap())
+            .send_string(&serde_json::json!({ "presence": presence }).to_string())?;
+
+        Ok(())
     }
 }
 
-fn main() -> Result<(), ureq::Error> {
-    let config = {
-        if Path::exists(Path::new("./config.json")) {
-            // read config from file
-            config::Config::from_file("./config.json").unwrap()
-        } else {
-            println!("no config file, using defaults");
-            config::Config::default()
+/// Prints a message the way it would look, with the HTML we'd send along with it (if any).
+fn print_event(content: &Json) {
+    let body = content["body"].as_str().unwrap_or_default();
+    match content["msgtype"].as_str() {
+        Some("m.text") | Some("m.notice") => println!("{}", body),
+        msgtype => println!(
+            "[{}] {} {}",
+            msgtype.unwrap_or("?"),
+            body,
+            content["url"].as_str().unwrap_or_default()
+        ),
+    }
+    if let Some(html) = content["formatted_body"].as_str() {
+        for line in html.trim_end().lines() {
+            println!("  | {}", line);
+        }
+    }
+}
+
+/// The gif providers that are set up in the config.
+fn gif_providers(config: &config::Config) -> gif::Providers {
+    let mut gif_providers = gif::Providers::default();
+    match &config.tenor_api_key {
+        None => println!("running without tenor gif functionality"),
+        Some(key) => gif_providers.add(Box::new(gif::Tenor::new(key.clone()))),
+    }
+    match &config.giphy_api_key {
+        None => println!("running without giphy gif functionality"),
+        Some(key) => gif_providers.add(Box::new(gif::Giphy::new(key.clone()))),
+    }
+    if let Some(dir) = &config.local_gifs {
+        match gif::Local::open(Path::new(dir)) {
+            Err(err) => eprintln!("running without local gifs, couldn't open {dir}: {err}"),
+            Ok(local) => gif_providers.add(Box::new(local)),
         }
+    }
+    gif_providers
+}
+
+/// Stand-ins for the gif providers that need the network, keeping their gifs in `dir`, along
+/// with the local library if there is one.
+fn offline_gif_providers(config: &config::Config, dir: &Path) -> gif::Providers {
+    let mut gif_providers = gif::Providers::default();
+    if let Some(dir) = &config.local_gifs {
+        match gif::Local::open(Path::new(dir)) {
+            Err(err) => eprintln!("running without local gifs, couldn't open {dir}: {err}"),
+            Ok(local) => gif_providers.add(Box::new(local)),
+        }
+    }
+    for name in gif::PROVIDER_NAMES {
+        if *name == "local" && config.local_gifs.is_some() {
+            continue;
+        }
+        match gif::Fixture::new(name, dir) {
+            Err(err) => eprintln!("running without {name} gifs, couldn't make one: {err}"),
+            Ok(fixture) => gif_providers.add(Box::new(fixture)),
+        }
+    }
+    gif_providers
+}
+
+/// Prints the docs `!help <query>` would link to, looking up each word on its own like the
+/// command does, and returns whether they were all found.
+fn print_help(query: &str) -> bool {
+    let parser = command::CommandParser::new();
+    let line = format!("help {}", query);
+    let Some(command::Command::Help { docs }) = parser.parse_mention(&line, "") else {
+        println!("No help found for {}", query);
+        return false;
     };
 
-    let user = std::env::var("MATRIX_USERNAME")
-        .expect("Please set the environment variable MATRIX_USERNAME");
+    let mut found_all = true;
+    for doc in docs {
+        match help::help(&doc) {
+            Some(tag) => println!("{}: {} in {}: {}", doc, tag.name, tag.file, tag.to_url()),
+            None => {
+                println!("No help found for {}", doc);
+                found_all = false;
+            }
+        }
+    }
+    found_all
+}
 
-    let password = std::env::var("MATRIX_PASSWORD")
-        .expect("Please set the environment variable MATRIX_PASSWORD");
+/// Prints what `!gif <query>` would find (in a room that only has the default settings),
+/// without downloading or sending anything.
+fn print_gifs(config: &config::Config, query: &str) {
+    // Rooms that aren't in the config have the default settings
+    let room_id = "";
+    if config.is_blocked_gif_search(room_id, query) {
+        println!("{} is blocked", query);
+        return;
+    }
 
-    let homeserver = {
-        match std::env::var("MATRIX_HOMESERVER") {
-            Err(_) => {
-                println!("defaulting to https://matrix.org for homeserver");
-                String::from(DEFAULT_HOMESERVER)
+    let providers = gif_providers(config);
+    let agent = ureq::AgentBuilder::new().build();
+    let gifs = providers.search(
+        &agent,
+        &config.gif_providers(room_id, None),
+        query,
+        config.gif_rating(room_id),
+    );
+    if gifs.is_empty() {
+        println!("No gifs found for {}", query);
+    }
+    for gif in gifs {
+        println!("{}", gif.attribution);
+        for rendition in gif.renditions(config.gif_video(room_id)) {
+            let source = match &rendition.source {
+                gif::Source::Url(url) => url.clone(),
+                gif::Source::File(path) => path.display().to_string(),
+            };
+            println!(
+                "  {} {}x{}, {} bytes: {}",
+                rendition.mimetype, rendition.width, rendition.height, rendition.size, source
+            );
+        }
+    }
+}
+
+fn main() -> Result<(), ureq::Error> {
+    let cli = match cli::parse(std::env::args().skip(1)) {
+        Ok(cli) => cli,
+        Err(err) => {
+            eprintln!("{}\n\n{}", err, cli::USAGE);
+            std::process::exit(2);
+        }
+    };
+    match &cli.subcommand {
+        cli::Subcommand::Usage => {
+            println!("{}", cli::USAGE);
+            return Ok(());
+        }
+        // This doesn't use the config, so it works even when the config doesn't
+        cli::Subcommand::Help(query) => {
+            if !print_help(query) {
+                std::process::exit(1);
             }
-            Ok(homeserver) => homeserver,
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    let config_path = cli
+        .config
+        .or_else(|| std::env::var_os(format!("{}CONFIG", config::ENV_PREFIX)).map(PathBuf::from))
+        .or_else(|| config::default_path().map(Path::to_path_buf));
+    let config_name = match &config_path {
+        Some(path) => path.display().to_string(),
+        None => "config".to_string(),
+    };
+    if config_path.is_none() {
+        println!("no config file, using defaults");
+    }
+    let config = match config::Config::load(config_path.as_deref()) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("Error in {}: {}", config_name, err);
+            std::process::exit(1);
         }
     };
 
-    let tenor_api_key = {
-        match std::env::var("TENOR_API_KEY") {
-            Err(_) => {
-                println!("running without tenor gif functionality");
-                None
-            }
-            Ok(key) => Some(key),
+    let credentials = |config: &config::Config| match config.credentials() {
+        Ok(credentials) => credentials,
+        Err(err) => {
+            eprintln!("Can't log in: {}", err);
+            std::process::exit(1);
         }
     };
 
-    let giphy_api_key = {
-        match std::env::var("GIPHY_API_KEY") {
-            Err(_) => {
-                println!("running without giphy gif functionality");
-                None
+    match cli.subcommand {
+        cli::Subcommand::Usage | cli::Subcommand::Help(_) => unreachable!(),
+        cli::Subcommand::CheckConfig => match &config_path {
+            Some(_) => println!("{} is fine", config_name),
+            None => {
+                println!("There's no config file, the defaults and environment variables are fine")
+            }
+        },
+        cli::Subcommand::Gif(query) => print_gifs(&config, &query),
+        cli::Subcommand::Repl { room_id, online } => {
+            // Somewhere to keep the REPL's state, so nothing it does sticks around
+            let mut config = config;
+            let state_dir =
+                std::env::temp_dir().join(format!("nvim-matrix-bot-repl-{}", std::process::id()));
+            config.state_dir = state_dir.display().to_string();
+
+            let gif_providers = if online {
+                gif_providers(&config)
+            } else {
+                offline_gif_providers(&config, &state_dir.join("gifs"))
+            };
+            let client = MatrixClient::new_offline(gif_providers, config);
+            let result = client.repl(room_id.as_deref().unwrap_or(OFFLINE_ROOM_ID));
+            _ = std::fs::remove_dir_all(state_dir);
+            if let Err(err) = result {
+                eprintln!("Error reading stdin: {}", err);
+                std::process::exit(1);
             }
-            Ok(key) => Some(key),
         }
-    };
+        cli::Subcommand::Login => {
+            let credentials = credentials(&config);
+            let state_dir = config.state_dir.clone();
+            let mut client =
+                MatrixClient::new(config.homeserver.clone(), gif::Providers::default(), config);
+            client.login(&credentials.username, &credentials.password)?;
 
-    let mut client = MatrixClient::new(homeserver, tenor_api_key, giphy_api_key, config);
-    client.login(&user, &password)?;
-    client.sync()?;
+            let session = client.session().expect("we just logged in");
+            match session.save(&state_dir) {
+                Ok(()) => println!(
+                    "Logged in as {}, saved the session to {}",
+                    session.user_id,
+                    state::Session::path(&state_dir).display()
+                ),
+                Err(err) => {
+                    eprintln!("Error saving the session: {}", err);
+                    std::process::exit(1);
+                }
+            }
+        }
+        cli::Subcommand::Run => {
+            // Without credentials, whatever session there is is who we are
+            let username = config
+                .credentials()
+                .ok()
+                .map(|credentials| credentials.username);
+            let session =
+                state::Session::load(&config.state_dir, &config.homeserver, username.as_deref());
+            let gif_providers = gif_providers(&config);
+            let mut client = MatrixClient::new(config.homeserver.clone(), gif_providers, config);
+
+            let logged_in = match session {
+                Some(session) => {
+                    client.use_session(session);
+                    client.whoami().map_err(|err| {
+                        eprintln!("The saved session doesn't work, logging in again: {}", err);
+                    })
+                }
+                None => Err(()),
+            };
+            if logged_in.is_err() {
+                let credentials = credentials(&client.config());
+                client.login(&credentials.username, &credentials.password)?;
+            }
+
+            // Stop after handling whatever's already come in, or right away if we're asked
+            // twice
+            let stop = Arc::new(AtomicBool::new(false));
+            for signal in [SIGINT, SIGTERM] {
+                signal_hook::flag::register_conditional_shutdown(signal, 1, Arc::clone(&stop))
+                    .and_then(|_| signal_hook::flag::register(signal, Arc::clone(&stop)))
+                    .expect("signal handlers can be registered");
+            }
+
+            client.upload_local_room_settings();
+            client.sync(&stop)?;
+        }
+    }
 
     Ok(())
 }
@@ -0,0 +1,629 @@
+//! Helpers for the media (gifs and such) we send.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value as Json;
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug)]
+pub enum MediaError {
+    Ureq(Box<ureq::Error>),
+    Json(serde_json::Error),
+    Io(std::io::Error),
+    /// The homeserver gave us something that isn't an mxc URI
+    InvalidMxcUri(String),
+    /// Media is bigger than the most we're willing to download (or allowed to upload)
+    TooLarge {
+        max: u64,
+    },
+    /// Media isn't what the provider said it is
+    WrongType {
+        declared: String,
+        actual: Option<&'static str>,
+    },
+}
+
+impl std::fmt::Display for MediaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MediaError::Ureq(err) => write!(f, "{}", err),
+            MediaError::Json(err) => write!(f, "bad response: {}", err),
+            MediaError::Io(err) => write!(f, "{}", err),
+            MediaError::InvalidMxcUri(uri) => write!(f, "invalid mxc URI '{}'", uri),
+            MediaError::TooLarge { max } => write!(f, "bigger than the {} byte limit", max),
+            MediaError::WrongType { declared, actual } => write!(
+                f,
+                "supposed to be {} but is {}",
+                declared,
+                actual.unwrap_or("something else")
+            ),
+        }
+    }
+}
+
+impl From<ureq::Error> for MediaError {
+    fn from(err: ureq::Error) -> Self {
+        MediaError::Ureq(Box::new(err))
+    }
+}
+
+impl From<std::io::Error> for MediaError {
+    fn from(err: std::io::Error) -> Self {
+        MediaError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for MediaError {
+    fn from(err: serde_json::Error) -> Self {
+        MediaError::Json(err)
+    }
+}
+
+/// A `mxc://<server-name>/<media-id>` URI, which is how Matrix refers to uploaded media.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MxcUri {
+    pub server_name: String,
+    pub media_id: String,
+}
+
+impl std::str::FromStr for MxcUri {
+    type Err = MediaError;
+
+    fn from_str(uri: &str) -> Result<Self, Self::Err> {
+        let invalid = || MediaError::InvalidMxcUri(uri.to_string());
+        let (server_name, media_id) = uri
+            .strip_prefix("mxc://")
+            .and_then(|rest| rest.split_once('/'))
+            .ok_or_else(invalid)?;
+
+        // Server names are hostnames, IPv4 or [IPv6] addresses, with an optional port
+        let valid_server_name = !server_name.is_empty()
+            && server_name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-.:[]".contains(c));
+        let valid_media_id = !media_id.is_empty()
+            && media_id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+        if !valid_server_name || !valid_media_id {
+            return Err(invalid());
+        }
+
+        Ok(Self {
+            server_name: server_name.to_string(),
+            media_id: media_id.to_string(),
+        })
+    }
+}
+
+impl std::fmt::Display for MxcUri {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "mxc://{}/{}", self.server_name, self.media_id)
+    }
+}
+
+/// How media gets uploaded to the homeserver.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UploadMode {
+    /// `POST /upload`, which every homeserver supports
+    #[default]
+    Direct,
+    /// `POST /create` for an mxc URI and then `PUT` the media to it, which needs a homeserver
+    /// with asynchronous upload support (Matrix 1.7)
+    Async,
+}
+
+/// Something to upload and what to call it.
+pub struct Upload<'a> {
+    pub bytes: &'a [u8],
+    pub content_type: &'a str,
+    pub filename: &'a str,
+}
+
+/// Uploads media to the homeserver, returning where it ended up.
+pub fn upload(
+    agent: &ureq::Agent,
+    homeserver: &str,
+    access_token: &str,
+    mode: UploadMode,
+    media: &Upload,
+) -> Result<MxcUri, MediaError> {
+    let content_uri = |response: ureq::Response| -> Result<MxcUri, MediaError> {
+        let json: Json = serde_json::from_str(&response.into_string()?)?;
+        json["content_uri"].as_str().unwrap_or_default().parse()
+    };
+
+    match mode {
+        UploadMode::Direct => content_uri(
+            agent
+                .post(&format!("{}/_matrix/media/v3/upload", homeserver))
+                .query("filename", media.filename)
+                .query("access_token", access_token)
+                .set("Content-Type", media.content_type)
+                .send_bytes(media.bytes)?,
+        ),
+        UploadMode::Async => {
+            let uri = content_uri(
+                agent
+                    .post(&format!("{}/_matrix/media/v1/create", homeserver))
+                    .set("Accept", "application/json")
+                    .query("access_token", access_token)
+                    .call()?,
+            )?;
+
+            agent
+                .put(&format!(
+                    "{}/_matrix/media/v3/upload/{}/{}",
+                    homeserver, uri.server_name, uri.media_id,
+                ))
+                .query("filename", media.filename)
+                .query("access_token", access_token)
+                .set("Content-Type", media.content_type)
+                .send_bytes(media.bytes)?;
+
+            Ok(uri)
+        }
+    }
+}
+
+/// A filename for media of type `mimetype` based on `name` (usually the gif search), like
+/// `dancing-cat.gif` for `Dancing cat!`.
+pub fn filename(name: &str, mimetype: &str) -> String {
+    let mut stem = String::new();
+    for c in name.chars().flat_map(char::to_lowercase) {
+        if c.is_alphanumeric() {
+            stem.push(c);
+        } else if !stem.is_empty() && !stem.ends_with('-') {
+            stem.push('-');
+        }
+        if stem.chars().count() >= 40 {
+            break;
+        }
+    }
+    let stem = stem.trim_end_matches('-');
+
+    let extension = match mimetype {
+        "image/gif" => "gif",
+        "image/png" => "png",
+        "image/jpeg" => "jpg",
+        "image/webp" => "webp",
+        "video/mp4" => "mp4",
+        "video/webm" => "webm",
+        _ => "bin",
+    };
+
+    format!(
+        "{}.{}",
+        if stem.is_empty() { "gif" } else { stem },
+        extension
+    )
+}
+
+/// What we could tell about an image from its first few bytes.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ImageInfo {
+    pub mimetype: &'static str,
+    pub width: i32,
+    pub height: i32,
+}
+
+fn u16_le(bytes: &[u8], at: usize) -> Option<i32> {
+    Some(u16::from_le_bytes(bytes.get(at..at + 2)?.try_into().ok()?) as i32)
+}
+
+fn u24_le(bytes: &[u8], at: usize) -> Option<i32> {
+    let b = bytes.get(at..at + 3)?;
+    Some(b[0] as i32 | (b[1] as i32) << 8 | (b[2] as i32) << 16)
+}
+
+fn u16_be(bytes: &[u8], at: usize) -> Option<i32> {
+    Some(u16::from_be_bytes(bytes.get(at..at + 2)?.try_into().ok()?) as i32)
+}
+
+fn u32_be(bytes: &[u8], at: usize) -> Option<i32> {
+    i32::try_from(u32::from_be_bytes(bytes.get(at..at + 4)?.try_into().ok()?)).ok()
+}
+
+/// Finds the dimensions of a JPEG in its first frame header, which comes after however much
+/// metadata the JPEG has.
+fn jpeg_size(bytes: &[u8]) -> Option<(i32, i32)> {
+    let mut at = 2;
+    loop {
+        if *bytes.get(at)? != 0xff {
+            return None;
+        }
+        match *bytes.get(at + 1)? {
+            // Padding
+            0xff => at += 1,
+            // Markers without a length
+            0x01 | 0xd0..=0xd7 => at += 2,
+            // Frame headers (the rest of 0xc0..=0xcf are other stuff)
+            0xc0..=0xcf if ![0xc4, 0xc8, 0xcc].contains(&bytes[at + 1]) => {
+                return Some((u16_be(bytes, at + 7)?, u16_be(bytes, at + 5)?));
+            }
+            _ => at += 2 + u16_be(bytes, at + 2)? as usize,
+        }
+    }
+}
+
+/// Figures out the type and dimensions of a GIF, PNG or WebP image from its header, which is
+/// in the first 32 bytes, or of a JPEG, which needs the whole thing.
+pub fn sniff(bytes: &[u8]) -> Option<ImageInfo> {
+    let (mimetype, width, height) = if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a")
+    {
+        ("image/gif", u16_le(bytes, 6)?, u16_le(bytes, 8)?)
+    } else if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        ("image/png", u32_be(bytes, 16)?, u32_be(bytes, 20)?)
+    } else if bytes.starts_with(b"\xff\xd8\xff") {
+        let (width, height) = jpeg_size(bytes)?;
+        ("image/jpeg", width, height)
+    } else if bytes.starts_with(b"RIFF") && bytes.get(8..12) == Some(b"WEBP") {
+        match bytes.get(12..16)? {
+            b"VP8X" => ("image/webp", u24_le(bytes, 24)? + 1, u24_le(bytes, 27)? + 1),
+            b"VP8 " => (
+                "image/webp",
+                u16_le(bytes, 26)? & 0x3fff,
+                u16_le(bytes, 28)? & 0x3fff,
+            ),
+            b"VP8L" => {
+                let bits = u32::from_le_bytes(bytes.get(21..25)?.try_into().ok()?);
+                (
+                    "image/webp",
+                    (bits & 0x3fff) as i32 + 1,
+                    ((bits >> 14) & 0x3fff) as i32 + 1,
+                )
+            }
+            _ => return None,
+        }
+    } else {
+        return None;
+    };
+
+    Some(ImageInfo {
+        mimetype,
+        width,
+        height,
+    })
+}
+
+/// Figures out the type of an image (like [`sniff`]) or an mp4 or WebM video.
+pub fn sniff_type(bytes: &[u8]) -> Option<&'static str> {
+    if bytes.get(4..8) == Some(b"ftyp") {
+        Some("video/mp4")
+    } else if bytes.starts_with(b"\x1a\x45\xdf\xa3") {
+        Some("video/webm")
+    } else {
+        sniff(bytes).map(|info| info.mimetype)
+    }
+}
+
+/// Makes sure `bytes` really are an image (or video) of type `declared`, returning the type.
+pub fn verify(bytes: &[u8], declared: &str) -> Result<&'static str, MediaError> {
+    match sniff_type(bytes) {
+        Some(mimetype) if mimetype == declared => Ok(mimetype),
+        actual => Err(MediaError::WrongType {
+            declared: declared.to_string(),
+            actual,
+        }),
+    }
+}
+
+/// Downloads media, giving up if it's bigger than `max_size` bytes.
+pub fn download(agent: &ureq::Agent, url: &str, max_size: u64) -> Result<Vec<u8>, MediaError> {
+    let response = agent.get(url).call()?;
+    // Don't bother downloading it at all if we already know it's too big
+    if response
+        .header("Content-Length")
+        .and_then(|len| len.parse::<u64>().ok())
+        .is_some_and(|len| len > max_size)
+    {
+        return Err(MediaError::TooLarge { max: max_size });
+    }
+
+    // The length might not be there (or be right), so stop reading once we're over the limit
+    let mut bytes = vec![];
+    response
+        .into_reader()
+        .take(max_size + 1)
+        .read_to_end(&mut bytes)?;
+    if bytes.len() as u64 > max_size {
+        return Err(MediaError::TooLarge { max: max_size });
+    }
+
+    Ok(bytes)
+}
+
+/// Reads media from a file, as long as it isn't bigger than `max_size` bytes.
+pub fn read_file(path: &Path, max_size: u64) -> Result<Vec<u8>, MediaError> {
+    if std::fs::metadata(path)?.len() > max_size {
+        return Err(MediaError::TooLarge { max: max_size });
+    }
+    Ok(std::fs::read(path)?)
+}
+
+/// The biggest upload the homeserver allows (`m.upload.size`), if it says.
+pub fn max_upload_size(
+    agent: &ureq::Agent,
+    homeserver: &str,
+    access_token: &str,
+) -> Result<Option<u64>, MediaError> {
+    let response = agent
+        .get(&format!("{}/_matrix/media/v3/config", homeserver))
+        .query("access_token", access_token)
+        .call()?;
+    let json: Json = serde_json::from_str(&response.into_string()?)?;
+    Ok(json["m.upload.size"].as_u64())
+}
+
+/// Hash of some media's contents, for telling if we've already uploaded it. This is FNV-1a
+/// plus the length, which is plenty to tell gifs apart and stays the same between builds
+/// (unlike std's hashers).
+pub fn content_hash(bytes: &[u8]) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("{}-{:016x}", bytes.len(), hash)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs())
+}
+
+/// A thumbnail we made and uploaded for some media.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct UploadedThumbnail {
+    pub mxc_uri: String,
+    pub width: u32,
+    pub height: u32,
+    pub size: u64,
+    pub blurhash: String,
+}
+
+/// Media we've uploaded, along with what we found out about it on the way. Everything but the
+/// mxc URI is missing for media cached before we started keeping track of it.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Uploaded {
+    pub mxc_uri: String,
+    /// What the media actually is
+    pub mimetype: Option<String>,
+    pub size: Option<u64>,
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    pub thumbnail: Option<UploadedThumbnail>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Cached {
+    #[serde(flatten)]
+    uploaded: Uploaded,
+    /// Unix timestamp (in seconds)
+    cached_at: u64,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct CacheFile {
+    /// By the URL the media was downloaded from
+    urls: HashMap<String, Cached>,
+    /// By [`content_hash`]
+    hashes: HashMap<String, Cached>,
+}
+
+/// Remembers media we've uploaded, so sending the same gif again doesn't mean
+/// downloading and uploading it again. Entries expire after `ttl` seconds, since homeservers
+/// don't necessarily keep media around forever.
+pub struct MediaCache {
+    path: PathBuf,
+    ttl: u64,
+    cache: CacheFile,
+}
+
+impl MediaCache {
+    pub fn load(path: PathBuf, ttl: u64) -> Self {
+        let cache = match std::fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|err| {
+                eprintln!("Ignoring broken media cache {}: {}", path.display(), err);
+                CacheFile::default()
+            }),
+            Err(_) => CacheFile::default(),
+        };
+
+        let mut cache = Self { path, ttl, cache };
+        cache.prune();
+        cache
+    }
+
+    fn prune(&mut self) {
+        let oldest = now().saturating_sub(self.ttl);
+        self.cache
+            .urls
+            .retain(|_, cached| cached.cached_at >= oldest);
+        self.cache
+            .hashes
+            .retain(|_, cached| cached.cached_at >= oldest);
+    }
+
+    fn save(&self) {
+        if let Err(err) = crate::state::save_json(&self.path, &self.cache) {
+            eprintln!("Error saving media cache {}: {}", self.path.display(), err);
+        }
+    }
+
+    pub fn get_url(&mut self, url: &str) -> Option<Uploaded> {
+        self.prune();
+        self.cache
+            .urls
+            .get(url)
+            .map(|cached| cached.uploaded.clone())
+    }
+
+    pub fn get_hash(&mut self, hash: &str) -> Option<Uploaded> {
+        self.prune();
+        self.cache
+            .hashes
+            .get(hash)
+            .map(|cached| cached.uploaded.clone())
+    }
+
+    /// Remembers that the media with `hash` (downloaded from `url`, if it was) got uploaded.
+    pub fn insert(&mut self, url: Option<&str>, hash: &str, uploaded: &Uploaded) {
+        let cached = Cached {
+            uploaded: uploaded.clone(),
+            cached_at: now(),
+        };
+        // Keep the original time for content we already had, so it still expires on time, and
+        // so does the URL it was downloaded from this time
+        let kept = self
+            .cache
+            .hashes
+            .entry(hash.to_string())
+            .or_insert(cached)
+            .clone();
+        if let Some(url) = url {
+            self.cache.urls.insert(url.to_string(), kept);
+        }
+
+        self.save();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sniff_works() {
+        let gif = b"GIF89a\x40\x01\xf0\x00\x00\x00";
+        assert_eq!(
+            sniff(gif),
+            Some(ImageInfo {
+                mimetype: "image/gif",
+                width: 320,
+                height: 240,
+            })
+        );
+
+        let png = b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR\x00\x00\x01\x00\x00\x00\x00\x80";
+        assert_eq!(
+            sniff(png),
+            Some(ImageInfo {
+                mimetype: "image/png",
+                width: 256,
+                height: 128,
+            })
+        );
+
+        let webp =
+            b"RIFF\x00\x00\x00\x00WEBPVP8X\x0a\x00\x00\x00\x10\x00\x00\x00\x3f\x00\x00\x1f\x00\x00";
+        assert_eq!(
+            sniff(webp),
+            Some(ImageInfo {
+                mimetype: "image/webp",
+                width: 64,
+                height: 32,
+            })
+        );
+
+        let jpeg = b"\xff\xd8\xff\xe0\x00\x04\x00\x00\xff\xff\xc0\x00\x11\x08\x00\xf0\x01\x40\x03";
+        assert_eq!(
+            sniff(jpeg),
+            Some(ImageInfo {
+                mimetype: "image/jpeg",
+                width: 320,
+                height: 240,
+            })
+        );
+
+        assert_eq!(sniff(b"GIF89a"), None);
+        assert_eq!(sniff(b"\xff\xd8\xff\xe0\x00\x04\x00\x00"), None);
+        assert_eq!(sniff(b"<html>"), None);
+    }
+
+    #[test]
+    fn verify_works() {
+        let gif = b"GIF89a\x40\x01\xf0\x00\x00\x00";
+        assert!(verify(gif, "image/gif").is_ok());
+        assert!(matches!(
+            verify(gif, "image/png"),
+            Err(MediaError::WrongType {
+                actual: Some("image/gif"),
+                ..
+            })
+        ));
+        assert_eq!(
+            verify(b"\x00\x00\x00\x18ftypmp42", "video/mp4").ok(),
+            Some("video/mp4")
+        );
+        assert!(matches!(
+            verify(b"<html>", "image/gif"),
+            Err(MediaError::WrongType { actual: None, .. })
+        ));
+    }
+
+    #[test]
+    fn mxc_uris_work() {
+        let uri: MxcUri = "mxc://matrix.org:8448/AbC_12-x".parse().unwrap();
+        assert_eq!(uri.server_name, "matrix.org:8448");
+        assert_eq!(uri.media_id, "AbC_12-x");
+        assert_eq!(uri.to_string(), "mxc://matrix.org:8448/AbC_12-x");
+
+        for invalid in [
+            "",
+            "mxc://",
+            "mxc://matrix.org",
+            "mxc://matrix.org/",
+            "mxc:///abc",
+            "mxc://matrix.org/abc/def",
+            "https://matrix.org/abc",
+            "mxc://matrix.org/abc?x=1",
+        ] {
+            assert!(invalid.parse::<MxcUri>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn filenames_work() {
+        assert_eq!(filename("Dancing cat!", "image/gif"), "dancing-cat.gif");
+        assert_eq!(filename("  ship   it ", "image/webp"), "ship-it.webp");
+        assert_eq!(filename("!!!", "image/jpeg"), "gif.jpg");
+        assert_eq!(filename("x", "text/html"), "x.bin");
+        assert_eq!(filename(&"a".repeat(100), "image/png").len(), 44);
+    }
+
+    #[test]
+    fn content_hash_works() {
+        assert_eq!(content_hash(b""), "0-cbf29ce484222325");
+        assert_eq!(content_hash(b"a"), "1-af63dc4c8601ec8c");
+        assert_ne!(content_hash(b"ab"), content_hash(b"ba"));
+    }
+
+    #[test]
+    fn cached_urls_expire_with_their_content() {
+        let path = std::env::temp_dir().join(format!("media-cache-{}.json", std::process::id()));
+        let mut cache = MediaCache::load(path.clone(), 100);
+        let uploaded = |mxc_uri: &str| Uploaded {
+            mxc_uri: mxc_uri.to_string(),
+            mimetype: Some("image/gif".to_string()),
+            size: Some(1),
+            width: None,
+            height: None,
+            thumbnail: None,
+        };
+
+        cache.insert(Some("https://a"), "hash", &uploaded("mxc://a/1"));
+        cache.cache.hashes.get_mut("hash").unwrap().cached_at -= 60;
+        cache.insert(Some("https://b"), "hash", &uploaded("mxc://a/2"));
+        assert_eq!(cache.get_url("https://b").unwrap().mxc_uri, "mxc://a/1");
+        assert_eq!(
+            cache.cache.urls["https://b"].cached_at,
+            cache.cache.hashes["hash"].cached_at
+        );
+        _ = std::fs::remove_file(path);
+    }
+}
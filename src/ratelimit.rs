@@ -0,0 +1,125 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+type Key = (String, String, &'static str);
+
+/// Keeps people from spamming commands, with per-command cooldowns for each person in each
+/// room, and a limit on how many gifs get fetched per minute overall. Commands are handled one
+/// at a time, so that limits how fast gifs get fetched rather than how many at once.
+#[derive(Default)]
+pub struct RateLimiter {
+    /// When each (room, sender, command) can be used again
+    cooldowns: HashMap<Key, Instant>,
+    /// Until when we've already told each (room, sender, command) to slow down
+    notices: HashMap<Key, Instant>,
+    /// When the gifs fetched in the last minute were fetched, oldest first
+    gif_fetches: VecDeque<Instant>,
+}
+
+impl RateLimiter {
+    /// How much longer `sender` has to wait before using `command` in `room_id` again. If they
+    /// don't have to, the command's `cooldown` starts now.
+    pub fn wait(
+        &mut self,
+        room_id: &str,
+        sender: &str,
+        command: &'static str,
+        cooldown: Duration,
+    ) -> Option<Duration> {
+        let now = Instant::now();
+        self.cooldowns.retain(|_, until| *until > now);
+
+        let key = (room_id.to_string(), sender.to_string(), command);
+        if let Some(until) = self.cooldowns.get(&key) {
+            return Some(*until - now);
+        }
+        if !cooldown.is_zero() {
+            self.cooldowns.insert(key, now + cooldown);
+        }
+        None
+    }
+
+    /// Whether to tell `sender` that they're being throttled, which only happens once until
+    /// `quiet_for` is up so that being told to slow down isn't spam itself.
+    pub fn notify(
+        &mut self,
+        room_id: &str,
+        sender: &str,
+        command: &'static str,
+        quiet_for: Duration,
+    ) -> bool {
+        let now = Instant::now();
+        self.notices.retain(|_, until| *until > now);
+
+        let key = (room_id.to_string(), sender.to_string(), command);
+        if self.notices.contains_key(&key) {
+            return false;
+        }
+        self.notices.insert(key, now + quiet_for);
+        true
+    }
+
+    /// Uses up one of the `per_minute` gif fetches allowed. If they've all been used in the
+    /// last minute, returns how long until one frees up instead.
+    pub fn fetch_gif(&mut self, per_minute: usize) -> Option<Duration> {
+        let now = Instant::now();
+        let minute = Duration::from_secs(60);
+        while self
+            .gif_fetches
+            .front()
+            .is_some_and(|fetched| now.duration_since(*fetched) >= minute)
+        {
+            self.gif_fetches.pop_front();
+        }
+
+        if self.gif_fetches.len() >= per_minute {
+            let oldest = self.gif_fetches.front().copied().unwrap_or(now);
+            return Some(minute.saturating_sub(now.duration_since(oldest)));
+        }
+        self.gif_fetches.push_back(now);
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn cooldowns_work() {
+        let mut limiter = RateLimiter::default();
+        let minute = Duration::from_secs(60);
+
+        assert_eq!(limiter.wait("!room", "@a", "gif", minute), None);
+        assert!(limiter.wait("!room", "@a", "gif", minute).is_some());
+        // Other people, rooms and commands have their own cooldowns
+        assert_eq!(limiter.wait("!room", "@b", "gif", minute), None);
+        assert_eq!(limiter.wait("!other", "@a", "gif", minute), None);
+        assert_eq!(limiter.wait("!room", "@a", "help", minute), None);
+
+        // No cooldown means no waiting
+        assert_eq!(limiter.wait("!room", "@a", "url", Duration::ZERO), None);
+        assert_eq!(limiter.wait("!room", "@a", "url", Duration::ZERO), None);
+    }
+
+    #[test]
+    fn gif_fetches_are_limited() {
+        let mut limiter = RateLimiter::default();
+        assert_eq!(limiter.fetch_gif(2), None);
+        assert_eq!(limiter.fetch_gif(2), None);
+        assert!(limiter.fetch_gif(2).is_some());
+        assert!(limiter.fetch_gif(0).is_some());
+    }
+
+    #[test]
+    fn notices_are_sent_once() {
+        let mut limiter = RateLimiter::default();
+        let minute = Duration::from_secs(60);
+
+        assert!(limiter.notify("!room", "@a", "gif", minute));
+        assert!(!limiter.notify("!room", "@a", "gif", minute));
+        assert!(limiter.notify("!room", "@b", "gif", minute));
+        assert!(limiter.notify("!room", "@a", "help", Duration::ZERO));
+        assert!(limiter.notify("!room", "@a", "help", Duration::ZERO));
+    }
+}
@@ -0,0 +1,120 @@
+use std::collections::{HashMap, VecDeque};
+
+/// How many of the events we've replied to are remembered
+const MAX_TRACKED: usize = 1_000;
+
+struct Replied {
+    /// Who sent the message we replied to
+    sender: String,
+    /// Our replies, along with the [`Command::key`] of the command each one is for
+    ///
+    /// [`Command::key`]: crate::command::Command::key
+    replies: Vec<(String, String)>,
+}
+
+/// Keeps track of which events we replied to and with which events, so our replies can be
+/// updated when the message they're for is edited or redacted. Only the [`MAX_TRACKED`] most
+/// recent events are remembered.
+#[derive(Default)]
+pub struct Replies {
+    order: VecDeque<String>,
+    replied: HashMap<String, Replied>,
+    /// Maps our replies back to the events they're replies to
+    reply_to: HashMap<String, String>,
+}
+
+impl Replies {
+    /// Our replies to `event_id` and the commands they're for, oldest first.
+    pub fn get(&self, event_id: &str) -> &[(String, String)] {
+        self.replied
+            .get(event_id)
+            .map_or(&[], |replied| &replied.replies)
+    }
+
+    /// The command that `reply_id` is for.
+    pub fn command(&self, reply_id: &str) -> Option<&str> {
+        let replied = self.replied.get(self.reply_to.get(reply_id)?)?;
+        replied
+            .replies
+            .iter()
+            .find(|(reply, _)| reply == reply_id)
+            .map(|(_, command)| command.as_str())
+    }
+
+    /// Who sent `event_id`, if we replied to it.
+    pub fn sender(&self, event_id: &str) -> Option<&str> {
+        self.replied
+            .get(event_id)
+            .map(|replied| replied.sender.as_str())
+    }
+
+    /// Who sent the message that `reply_id` is a reply to.
+    pub fn requester(&self, reply_id: &str) -> Option<&str> {
+        self.sender(self.reply_to.get(reply_id)?)
+    }
+
+    /// The event that `reply_id` is a reply to.
+    pub fn replied_to(&self, reply_id: &str) -> Option<&str> {
+        self.reply_to.get(reply_id).map(String::as_str)
+    }
+
+    /// Remembers that `reply_id` answers `command` in `event_id`, which replaces whatever
+    /// command it answered before if it's an edited reply.
+    pub fn push(&mut self, event_id: &str, sender: &str, command: &str, reply_id: String) {
+        if let Some(replied) = self.replied.get_mut(event_id) {
+            match replied
+                .replies
+                .iter_mut()
+                .find(|(reply, _)| *reply == reply_id)
+            {
+                Some((_, old_command)) => *old_command = command.to_string(),
+                None => {
+                    self.reply_to.insert(reply_id.clone(), event_id.to_string());
+                    replied.replies.push((reply_id, command.to_string()));
+                }
+            }
+            return;
+        }
+
+        if self.order.len() == MAX_TRACKED {
+            if let Some(oldest) = self.order.front().cloned() {
+                self.remove(&oldest);
+            }
+        }
+        self.order.push_back(event_id.to_string());
+        self.reply_to.insert(reply_id.clone(), event_id.to_string());
+        self.replied.insert(
+            event_id.to_string(),
+            Replied {
+                sender: sender.to_string(),
+                replies: vec![(reply_id, command.to_string())],
+            },
+        );
+    }
+
+    /// Forgets about `event_id`, returning our replies to it.
+    pub fn remove(&mut self, event_id: &str) -> Vec<String> {
+        let Some(replied) = self.replied.remove(event_id) else {
+            return vec![];
+        };
+        self.order.retain(|id| id != event_id);
+        for (reply, _) in &replied.replies {
+            self.reply_to.remove(reply);
+        }
+        replied
+            .replies
+            .into_iter()
+            .map(|(reply, _)| reply)
+            .collect()
+    }
+
+    /// Forgets about one of our replies, e.g. because it got redacted.
+    pub fn remove_reply(&mut self, reply_id: &str) {
+        let Some(event_id) = self.reply_to.remove(reply_id) else {
+            return;
+        };
+        if let Some(replied) = self.replied.get_mut(&event_id) {
+            replied.replies.retain(|(reply, _)| reply != reply_id);
+        }
+    }
+}
@@ -0,0 +1,266 @@
+//! Finds `:help` references in message bodies, both plain (markdown) ones and the
+//! `formatted_body` HTML that clients send along with them.
+//!
+//! References are only picked up from code (inline code spans and code blocks) and from
+//! prose lines that *start* with `:h`, so stuff like "you can use :h for that" doesn't
+//! trigger anything.
+
+use pulldown_cmark::{Event, Parser, Tag};
+
+#[derive(Default)]
+struct Scanner {
+    refs: Vec<String>,
+    line: String,
+}
+
+impl Scanner {
+    /// A line of prose ended, so check if it starts with a help reference.
+    fn end_line(&mut self) {
+        let line = std::mem::take(&mut self.line);
+        self.refs.extend(help_refs(&line, true));
+    }
+
+    fn code(&mut self, code: &str) {
+        for line in code.lines() {
+            self.refs.extend(help_refs(line, false));
+        }
+    }
+
+    /// Inline code that's part of a line of prose, which we keep around (still wrapped in
+    /// backticks) so the rest of the line isn't mistaken for its start.
+    fn inline_code(&mut self, code: &str) {
+        self.code(code);
+        self.line.push('`');
+        self.line.push_str(code);
+        self.line.push('`');
+    }
+
+    fn text(&mut self, text: &str) {
+        let mut lines = text.split('\n');
+        if let Some(first) = lines.next() {
+            self.line.push_str(first);
+        }
+        for line in lines {
+            self.end_line();
+            self.line.push_str(line);
+        }
+    }
+
+    fn finish(mut self) -> Vec<String> {
+        self.end_line();
+
+        let mut refs: Vec<String> = vec![];
+        for r in self.refs {
+            if !refs.contains(&r) {
+                refs.push(r);
+            }
+        }
+        refs
+    }
+}
+
+/// If `segment` looks like `:help foo` (the colon being optional when `colon_required` is
+/// false), returns the `foo` part.
+fn help_arg(segment: &str, colon_required: bool) -> Option<&str> {
+    let segment = segment.trim_start();
+    let segment = match segment.strip_prefix(':') {
+        Some(segment) => segment,
+        None if colon_required => return None,
+        None => segment,
+    };
+
+    let (cmd, arg) = segment.split_once(char::is_whitespace)?;
+    let arg = arg.trim();
+    if matches!(cmd, "help" | "h" | "he" | "hel") && !arg.is_empty() {
+        Some(arg)
+    } else {
+        None
+    }
+}
+
+/// Gets the help references out of a single line, which can chain several of them together
+/// like `:h foo | :h bar`. In prose only the first word after `:h` is used, since whatever
+/// follows it is most likely just the rest of the sentence.
+fn help_refs(line: &str, prose: bool) -> Vec<String> {
+    // `|` is a valid help topic on its own (`:h |`), so only treat it as a separator when
+    // another help command follows it.
+    let mut segments: Vec<String> = vec![];
+    for (i, part) in line.split('|').enumerate() {
+        match segments.last_mut() {
+            Some(last) if i > 0 && help_arg(part, false).is_none() => {
+                last.push('|');
+                last.push_str(part);
+            }
+            _ => segments.push(part.to_string()),
+        }
+    }
+
+    segments
+        .iter()
+        .enumerate()
+        .filter_map(|(i, segment)| help_arg(segment, i == 0))
+        .filter_map(|arg| {
+            if prose {
+                let word = arg.split_whitespace().next()?;
+                let trimmed = word.trim_end_matches(['.', ',', ';', '!', '?']);
+                Some(if trimmed.is_empty() { word } else { trimmed })
+            } else {
+                Some(arg)
+            }
+        })
+        .map(String::from)
+        .collect()
+}
+
+/// Finds help references in a plain (markdown) message body.
+pub fn markdown_help_refs(body: &str) -> Vec<String> {
+    let mut scanner = Scanner::default();
+    let mut code_block: Option<String> = None;
+
+    for event in Parser::new(body) {
+        match event {
+            Event::Start(Tag::CodeBlock(_)) => code_block = Some(String::new()),
+            Event::End(Tag::CodeBlock(_)) => {
+                if let Some(code) = code_block.take() {
+                    scanner.code(&code);
+                }
+            }
+            Event::Text(text) => match &mut code_block {
+                Some(code) => code.push_str(&text),
+                None => scanner.text(&text),
+            },
+            Event::Code(code) => scanner.inline_code(&code),
+            Event::SoftBreak | Event::HardBreak => scanner.end_line(),
+            Event::Start(Tag::Emphasis | Tag::Strong | Tag::Strikethrough | Tag::Link(..))
+            | Event::End(Tag::Emphasis | Tag::Strong | Tag::Strikethrough | Tag::Link(..)) => {}
+            Event::Start(_) | Event::End(_) | Event::Rule => scanner.end_line(),
+            _ => {}
+        }
+    }
+
+    scanner.finish()
+}
+
+/// Decodes the handful of HTML entities clients actually use in `formatted_body`.
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let entity = rest.find(';').map(|end| (&rest[1..end], end));
+        let c = entity.and_then(|(name, _)| match name {
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "amp" => Some('&'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some(' '),
+            _ => {
+                let num = name.strip_prefix('#')?;
+                let num = match num.strip_prefix(['x', 'X']) {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                    None => num.parse().ok()?,
+                };
+                char::from_u32(num)
+            }
+        });
+
+        match (c, entity) {
+            (Some(c), Some((_, end))) => {
+                decoded.push(c);
+                rest = &rest[end + 1..];
+            }
+            _ => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+/// Finds help references in a `formatted_body` (HTML) message body.
+pub fn html_help_refs(body: &str) -> Vec<String> {
+    let mut scanner = Scanner::default();
+    let mut code: Option<String> = None;
+    let mut rest = body;
+
+    while !rest.is_empty() {
+        let (text, after) = match rest.find('<') {
+            Some(start) => rest.split_at(start),
+            None => (rest, ""),
+        };
+        let text = decode_entities(text);
+        match &mut code {
+            Some(code) => code.push_str(&text),
+            None => scanner.text(&text),
+        }
+
+        let Some(end) = after.find('>') else {
+            break;
+        };
+        let tag = after[1..end].trim();
+        rest = &after[end + 1..];
+
+        let (closing, tag) = match tag.strip_prefix('/') {
+            Some(tag) => (true, tag),
+            None => (false, tag),
+        };
+        let name = tag
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+
+        match (name.as_str(), closing) {
+            ("code", false) => code = Some(String::new()),
+            ("code", true) => {
+                if let Some(code) = code.take() {
+                    scanner.inline_code(&code);
+                }
+            }
+            (
+                "br" | "p" | "div" | "pre" | "li" | "ul" | "ol" | "blockquote" | "h1" | "h2" | "h3"
+                | "h4" | "h5" | "h6" | "hr",
+                _,
+            ) if code.is_none() => scanner.end_line(),
+            _ => {}
+        }
+    }
+
+    scanner.finish()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn markdown_refs() {
+        assert_eq!(markdown_help_refs("`:h foo bar`"), ["foo bar"]);
+        assert_eq!(markdown_help_refs(":help foo"), ["foo"]);
+        assert_eq!(markdown_help_refs("hey\n:h foo. it's over there"), ["foo"]);
+        assert_eq!(
+            markdown_help_refs("```vim\n:h foo | :h bar\n```"),
+            ["foo", "bar"]
+        );
+        assert_eq!(markdown_help_refs("`:h |`"), ["|"]);
+        assert!(markdown_help_refs("you can use :h foo for that").is_empty());
+        assert!(markdown_help_refs("`h foo`").is_empty());
+    }
+
+    #[test]
+    fn html_refs() {
+        assert_eq!(html_help_refs("<code>:h &lt;C-w&gt;v</code>"), ["<C-w>v"]);
+        assert_eq!(
+            html_help_refs("<pre><code class=\"language-vim\">:help foo\n:h bar\n</code></pre>"),
+            ["foo", "bar"]
+        );
+        assert_eq!(html_help_refs("hi<br>:h foo &amp; stuff"), ["foo"]);
+        assert_eq!(html_help_refs("<p>:h foo | :h bar</p>"), ["foo", "bar"]);
+        assert!(html_help_refs("<p>try :h foo</p>").is_empty());
+    }
+}
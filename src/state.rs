@@ -0,0 +1,247 @@
+//! Stuff the bot remembers between runs, which lives in the `state_dir` from the config.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value as Json;
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config::{Config, RoomId};
+
+/// Saves `value` as JSON, writing somewhere else first so a crash halfway through doesn't
+/// leave a broken file behind.
+pub fn save_json(path: &Path, value: &impl Serialize) -> std::io::Result<()> {
+    write_json(path, value, false)
+}
+
+/// Like [`save_json`], but (on Unix) only we can read the file, for secrets like access
+/// tokens.
+pub fn save_private_json(path: &Path, value: &impl Serialize) -> std::io::Result<()> {
+    write_json(path, value, true)
+}
+
+fn write_json(path: &Path, value: &impl Serialize, private: bool) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension("tmp");
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    if private {
+        // The mode only counts when the file gets created, so don't reuse one left behind
+        _ = std::fs::remove_file(&tmp);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    }
+    options
+        .open(&tmp)?
+        .write_all(&serde_json::to_vec(value).map_err(std::io::Error::from)?)?;
+    std::fs::rename(tmp, path)
+}
+
+/// The type of the room account data room settings are kept in, so they follow the bot's
+/// account around instead of being stuck on whatever machine it was running on
+pub const ACCOUNT_DATA_TYPE: &str = "io.github.smolck.nvim_bot.settings";
+
+/// Room settings changed with `!config`. They're kept apart from the config file (which never
+/// gets rewritten) and override whatever it says. They're really stored in room account data
+/// (see [`ACCOUNT_DATA_TYPE`]), this is just a copy to start with until we've synced.
+pub struct RoomSettings {
+    path: PathBuf,
+    rooms: HashMap<RoomId, serde_json::Map<String, Json>>,
+}
+
+impl RoomSettings {
+    pub fn load(path: PathBuf) -> Self {
+        let rooms = match std::fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|err| {
+                eprintln!("Ignoring broken room settings {}: {}", path.display(), err);
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+
+        Self { path, rooms }
+    }
+
+    /// Puts the settings for every room on top of `config`.
+    pub fn apply(&self, config: &mut Config) {
+        for (room_id, settings) in &self.rooms {
+            if let Err(err) = config.set_room_settings(room_id, settings) {
+                eprintln!("Ignoring broken settings for {}: {}", room_id, err);
+            }
+        }
+    }
+
+    pub fn get(&self, room_id: &str) -> Option<&serde_json::Map<String, Json>> {
+        self.rooms.get(room_id)
+    }
+
+    pub fn rooms(&self) -> impl Iterator<Item = &RoomId> {
+        self.rooms.keys()
+    }
+
+    /// Replaces all of a room's settings.
+    pub fn set(&mut self, room_id: &str, settings: serde_json::Map<String, Json>) {
+        if self.rooms.get(room_id) == Some(&settings) {
+            return;
+        }
+        self.rooms.insert(room_id.to_string(), settings);
+
+        if let Err(err) = save_json(&self.path, &self.rooms) {
+            eprintln!(
+                "Error saving room settings {}: {}",
+                self.path.display(),
+                err
+            );
+        }
+    }
+}
+
+/// A login saved by `nvim-matrix-bot login`, so `run` doesn't have to log in (and make a new
+/// device) every time.
+#[derive(Serialize, Deserialize)]
+pub struct Session {
+    pub homeserver: String,
+    pub user_id: String,
+    pub access_token: String,
+}
+
+impl Session {
+    pub fn path(state_dir: &str) -> PathBuf {
+        Path::new(state_dir).join("session.json")
+    }
+
+    /// Whether the session is for `username`, which is either a user ID or just the local
+    /// part of one (like with logging in).
+    pub fn is_for(&self, username: &str) -> bool {
+        let local_part = self
+            .user_id
+            .strip_prefix('@')
+            .and_then(|id| id.split(':').next());
+        self.user_id == username || local_part == Some(username)
+    }
+
+    /// The saved session, if there's one for `homeserver` (and `username`, if we know who
+    /// we're supposed to be).
+    pub fn load(state_dir: &str, homeserver: &str, username: Option<&str>) -> Option<Self> {
+        let path = Self::path(state_dir);
+        let contents = std::fs::read_to_string(&path).ok()?;
+        let session: Self = serde_json::from_str(&contents)
+            .map_err(|err| eprintln!("Ignoring broken session {}: {}", path.display(), err))
+            .ok()?;
+        let right_user = username.is_none_or(|username| session.is_for(username));
+        (session.homeserver == homeserver && right_user).then_some(session)
+    }
+
+    pub fn save(&self, state_dir: &str) -> std::io::Result<()> {
+        // It's as good as a password
+        save_private_json(&Self::path(state_dir), self)
+    }
+}
+
+/// Where we got up to syncing, so messages sent while the bot was being restarted still get
+/// handled.
+#[derive(Serialize, Deserialize)]
+pub struct SyncPosition {
+    pub user_id: String,
+    pub next_batch: String,
+    /// When this was saved, in seconds since the Unix epoch. Files from before this was
+    /// saved count as ancient.
+    #[serde(default)]
+    saved_at: u64,
+}
+
+impl SyncPosition {
+    pub fn new(user_id: String, next_batch: String) -> Self {
+        Self {
+            user_id,
+            next_batch,
+            saved_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        }
+    }
+
+    fn path(state_dir: &str) -> PathBuf {
+        Path::new(state_dir).join("sync.json")
+    }
+
+    /// Where `user_id` got up to syncing, if we know.
+    pub fn load(state_dir: &str, user_id: &str) -> Option<Self> {
+        let contents = std::fs::read_to_string(Self::path(state_dir)).ok()?;
+        let position: Self = serde_json::from_str(&contents).ok()?;
+        (position.user_id == user_id).then_some(position)
+    }
+
+    /// How long ago this was saved.
+    pub fn age(&self) -> Duration {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH + Duration::from_secs(self.saved_at))
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self, state_dir: &str) -> std::io::Result<()> {
+        save_json(&Self::path(state_dir), self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sessions_work() {
+        let session = Session {
+            homeserver: "https://matrix.org".to_string(),
+            user_id: "@nvim-bot:matrix.org".to_string(),
+            access_token: "secret".to_string(),
+        };
+        assert!(session.is_for("nvim-bot"));
+        assert!(session.is_for("@nvim-bot:matrix.org"));
+        assert!(!session.is_for("someone-else"));
+        assert!(!session.is_for("@nvim-bot:example.org"));
+
+        let state_dir = std::env::temp_dir().join(format!("session-{}", std::process::id()));
+        let state_dir = state_dir.to_str().unwrap();
+        session.save(state_dir).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let metadata = std::fs::metadata(Session::path(state_dir)).unwrap();
+            assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        }
+        assert!(Session::load(state_dir, "https://matrix.org", Some("nvim-bot")).is_some());
+        assert!(Session::load(state_dir, "https://matrix.org", None).is_some());
+        assert!(Session::load(state_dir, "https://matrix.org", Some("someone")).is_none());
+        assert!(Session::load(state_dir, "https://example.org", None).is_none());
+        _ = std::fs::remove_dir_all(state_dir);
+    }
+
+    #[test]
+    fn sync_positions_work() {
+        let state_dir = std::env::temp_dir().join(format!("sync-{}", std::process::id()));
+        let state_dir = state_dir.to_str().unwrap();
+        std::fs::create_dir_all(state_dir).unwrap();
+        let position = SyncPosition::new("@nvim-bot:matrix.org".to_string(), "s1".to_string());
+        assert!(position.age() < Duration::from_secs(60));
+        position.save(state_dir).unwrap();
+
+        let loaded = SyncPosition::load(state_dir, "@nvim-bot:matrix.org").unwrap();
+        assert_eq!(loaded.next_batch, "s1");
+        assert!(loaded.age() < Duration::from_secs(60));
+        assert!(SyncPosition::load(state_dir, "@someone:matrix.org").is_none());
+
+        // From before positions had a time
+        std::fs::write(
+            SyncPosition::path(state_dir),
+            r#"{"user_id": "@nvim-bot:matrix.org", "next_batch": "s0"}"#,
+        )
+        .unwrap();
+        let loaded = SyncPosition::load(state_dir, "@nvim-bot:matrix.org").unwrap();
+        assert!(loaded.age() > Duration::from_secs(60 * 60 * 24 * 365));
+        _ = std::fs::remove_dir_all(state_dir);
+    }
+}
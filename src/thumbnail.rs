@@ -0,0 +1,172 @@
+//! Thumbnails (and [blurhashes](https://blurha.sh)) made from the first frame of a gif, so
+//! gifs get accurate previews even when the provider doesn't have any.
+
+/// Thumbnails fit in a square this big (in pixels)
+const MAX_SIZE: u32 = 320;
+/// Gifs with more pixels than this aren't worth decoding
+const MAX_PIXELS: u32 = 4096 * 4096;
+
+#[derive(Debug)]
+pub enum ThumbnailError {
+    Gif(gif::DecodingError),
+    Png(png::EncodingError),
+    Blurhash(blurhash::Error),
+    /// The gif has no frames, or is way too big
+    BadSize {
+        width: u32,
+        height: u32,
+    },
+}
+
+impl std::fmt::Display for ThumbnailError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ThumbnailError::Gif(err) => write!(f, "couldn't decode gif: {}", err),
+            ThumbnailError::Png(err) => write!(f, "couldn't encode png: {}", err),
+            ThumbnailError::Blurhash(err) => write!(f, "couldn't make blurhash: {}", err),
+            ThumbnailError::BadSize { width, height } => {
+                write!(f, "can't make a thumbnail of a {}x{} gif", width, height)
+            }
+        }
+    }
+}
+
+impl From<gif::DecodingError> for ThumbnailError {
+    fn from(err: gif::DecodingError) -> Self {
+        ThumbnailError::Gif(err)
+    }
+}
+
+impl From<png::EncodingError> for ThumbnailError {
+    fn from(err: png::EncodingError) -> Self {
+        ThumbnailError::Png(err)
+    }
+}
+
+impl From<blurhash::Error> for ThumbnailError {
+    fn from(err: blurhash::Error) -> Self {
+        ThumbnailError::Blurhash(err)
+    }
+}
+
+pub struct Thumbnail {
+    pub png: Vec<u8>,
+    pub width: u32,
+    pub height: u32,
+    pub blurhash: String,
+}
+
+/// Decodes the first frame of a gif as RGBA pixels, returning its width, height and pixels.
+fn first_frame(bytes: &[u8]) -> Result<(u32, u32, Vec<u8>), ThumbnailError> {
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::RGBA);
+    let mut decoder = options.read_info(bytes)?;
+
+    let (width, height) = (decoder.width() as u32, decoder.height() as u32);
+    let bad_size = ThumbnailError::BadSize { width, height };
+    if width == 0 || height == 0 || width * height > MAX_PIXELS {
+        return Err(bad_size);
+    }
+
+    // Frames can be smaller than the gif and go anywhere in it
+    let mut pixels = vec![0; (width * height * 4) as usize];
+    let frame = decoder.read_next_frame()?.ok_or(bad_size)?;
+    let (left, top) = (frame.left as u32, frame.top as u32);
+    let frame_width = (frame.width as u32).min(width.saturating_sub(left));
+    for y in 0..(frame.height as u32).min(height.saturating_sub(top)) {
+        let from = (y * frame.width as u32 * 4) as usize;
+        let to = (((top + y) * width + left) * 4) as usize;
+        let len = (frame_width * 4) as usize;
+        pixels[to..to + len].copy_from_slice(&frame.buffer[from..from + len]);
+    }
+
+    Ok((width, height, pixels))
+}
+
+/// Shrinks an image by averaging the pixels that end up in each new pixel.
+fn shrink(pixels: &[u8], width: u32, height: u32, new_width: u32, new_height: u32) -> Vec<u8> {
+    let mut shrunk = Vec::with_capacity((new_width * new_height * 4) as usize);
+    for y in 0..new_height {
+        let y0 = y * height / new_height;
+        let y1 = ((y + 1) * height / new_height).max(y0 + 1);
+        for x in 0..new_width {
+            let x0 = x * width / new_width;
+            let x1 = ((x + 1) * width / new_width).max(x0 + 1);
+
+            let mut sum = [0_u32; 4];
+            for old_y in y0..y1 {
+                for old_x in x0..x1 {
+                    let at = ((old_y * width + old_x) * 4) as usize;
+                    for (channel, sum) in sum.iter_mut().enumerate() {
+                        *sum += pixels[at + channel] as u32;
+                    }
+                }
+            }
+            let count = (y1 - y0) * (x1 - x0);
+            shrunk.extend(sum.map(|sum| (sum / count) as u8));
+        }
+    }
+    shrunk
+}
+
+/// Makes a PNG thumbnail and a blurhash from the first frame of a gif.
+pub fn generate(gif: &[u8]) -> Result<Thumbnail, ThumbnailError> {
+    let (width, height, pixels) = first_frame(gif)?;
+
+    let (width, height, pixels) = if width.max(height) > MAX_SIZE {
+        let new_width = (width * MAX_SIZE / width.max(height)).max(1);
+        let new_height = (height * MAX_SIZE / width.max(height)).max(1);
+        let pixels = shrink(&pixels, width, height, new_width, new_height);
+        (new_width, new_height, pixels)
+    } else {
+        (width, height, pixels)
+    };
+
+    let mut png = vec![];
+    let mut encoder = png::Encoder::new(&mut png, width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&pixels)?;
+    writer.finish()?;
+
+    Ok(Thumbnail {
+        png,
+        width,
+        height,
+        // 4x3 is what the blurhash docs suggest
+        blurhash: blurhash::encode(4, 3, width, height, &pixels)?,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn encode_gif(width: u16, height: u16) -> Vec<u8> {
+        let mut bytes = vec![];
+        let mut encoder = gif::Encoder::new(&mut bytes, width, height, &[]).unwrap();
+        let mut pixels: Vec<u8> = (0..width as usize * height as usize)
+            .flat_map(|i| [(i % 256) as u8, 0x80, 0xff, 0xff])
+            .collect();
+        encoder
+            .write_frame(&gif::Frame::from_rgba(width, height, &mut pixels))
+            .unwrap();
+        drop(encoder);
+        bytes
+    }
+
+    #[test]
+    fn thumbnails_work() {
+        let thumbnail = generate(&encode_gif(640, 200)).unwrap();
+        assert_eq!((thumbnail.width, thumbnail.height), (320, 100));
+        assert!(thumbnail.png.starts_with(b"\x89PNG\r\n\x1a\n"));
+        assert_eq!(thumbnail.blurhash.len(), 28);
+
+        // Small gifs are left alone
+        let thumbnail = generate(&encode_gif(16, 32)).unwrap();
+        assert_eq!((thumbnail.width, thumbnail.height), (16, 32));
+
+        assert!(generate(b"GIF89a").is_err());
+    }
+}